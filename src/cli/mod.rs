@@ -3,14 +3,49 @@ use clap_complete::{
     generate,
     shells::{Bash, Zsh},
 };
-use std::path::PathBuf;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
 use crate::error::{Error, Result};
-use crate::template::{TemplateContext, TemplateEngine};
+use crate::template::hooks::{self, hook_env};
+use crate::template::update::merge_update;
+use crate::template::values::{load_values_file, parse_set, Values};
+use crate::template::{archive, compose, snapshot};
+use crate::template::{
+    set_dir_modes, validate_username, write_files, write_project, Answers, CaseStatus, Conflict,
+    HookStage, Manifest, Rendered, RenderedFile, Template, TemplateContext, TemplateEngine,
+    TemplateSource, UpdateStatus, Variable, BUILTIN_VARIABLES, DEFAULT_USERNAME, DEFAULT_VENDOR,
+};
+
+pub mod preview;
+pub mod prompt;
+pub mod report;
+
+use prompt::Prompter;
+use report::{
+    DiffReport, Format, GeneratedFile, InfoReport, LintReport, NewReport, PackReport,
+    TemplateSummary, TestReport, UpdateReport, UpdatedFile,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    /// Directory of local templates, merged with the built-in ones
+    #[arg(long, global = true, env = "ESSEX_TEMPLATES_PATH")]
+    pub templates_dir: Option<PathBuf>,
+
+    /// Directory templates fetched from git are cached in
+    #[arg(long, global = true, env = "ESSEX_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Print errors as JSON on stderr, for CI and other tools
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Output format of the command's results
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -22,7 +57,9 @@ pub enum Commands {
 
     /// Create a new project from a template
     New {
-        /// Template to use
+        /// Template to use: a template name, or a git reference such as
+        /// gh:org/repo//path@tag or git+https://host/repo.git#path@tag. Join several with
+        /// '+', as in basic+compose, to layer them into one project.
         template: String,
 
         /// Project name in the format namespace/project
@@ -35,6 +72,78 @@ pub enum Commands {
         /// Vendor name for the project (optional)
         #[arg(short, long)]
         vendor: Option<String>,
+
+        /// Set a template variable (repeatable)
+        #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_set)]
+        set: Vec<(String, serde_json::Value)>,
+
+        /// Read template variables from a JSON, YAML or TOML file
+        #[arg(long = "values", value_name = "FILE")]
+        values_file: Option<PathBuf>,
+
+        /// Never prompt for missing values, even on a terminal
+        #[arg(long)]
+        no_input: bool,
+
+        /// Print the files that would be generated without writing anything
+        #[arg(long, conflicts_with = "diff")]
+        dry_run: bool,
+
+        /// Print a unified diff between the rendered output and an existing directory
+        /// (defaults to the project directory) without writing anything
+        #[arg(long, value_name = "DIR", num_args = 0..=1)]
+        diff: Option<Option<PathBuf>>,
+
+        /// Directory to generate the project into (defaults to ./<namespace>/<project>)
+        #[arg(short, long, value_name = "DIR", conflicts_with = "in_place")]
+        output: Option<PathBuf>,
+
+        /// Generate into the current directory
+        #[arg(long)]
+        in_place: bool,
+
+        /// Overwrite files that already exist
+        #[arg(long, group = "overwrite")]
+        force: bool,
+
+        /// Keep files that already exist and only write new ones
+        #[arg(long, group = "overwrite")]
+        skip_existing: bool,
+
+        /// Ask before overwriting each file that already exists
+        #[arg(long, group = "overwrite")]
+        prompt: bool,
+
+        /// Run the template's hooks without asking first
+        #[arg(long)]
+        trust: bool,
+
+        /// Layer another template on top of the previous ones (repeatable)
+        #[arg(long = "with", value_name = "TEMPLATE")]
+        with: Vec<String>,
+    },
+
+    /// Describe a template's variables and the files it produces, without generating it
+    Info {
+        /// Template to describe, as given to `essex new`
+        template: String,
+    },
+
+    /// Update a generated project to the current version of its template
+    Update {
+        /// Project directory to update
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Override a stored answer (repeatable)
+        #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_set)]
+        set: Vec<(String, serde_json::Value)>,
+    },
+
+    /// Work with template directories
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommands,
     },
 
     /// Generate shell completion scripts
@@ -49,6 +158,249 @@ pub enum Commands {
     },
 }
 
+#[derive(clap::Subcommand)]
+pub enum TemplateCommands {
+    /// Pack a template directory into a .tar.gz or .zip archive with a SHA-256 checksum
+    Pack {
+        /// Template directory to pack
+        dir: PathBuf,
+
+        /// Archive to write (defaults to ./<name>-<version>.tar.gz)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Check a template for syntax errors, undeclared variables and common mistakes
+    Lint {
+        /// Template directory, or a template as given to `essex new`
+        template: String,
+    },
+
+    /// Render a template's test cases from its tests/ directory and compare them to their
+    /// expected files
+    Test {
+        /// Template directory, or a template as given to `essex new`
+        template: String,
+
+        /// Replace the expected files of failing cases with the rendered files
+        #[arg(long)]
+        bless: bool,
+    },
+}
+
+/// What to do when generating into a directory that already has files in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Refuse unless the directory is empty or only holds a git repository
+    Fail,
+    /// Overwrite existing files
+    Force,
+    /// Keep existing files
+    SkipExisting,
+    /// Ask for each existing file
+    Prompt,
+}
+
+/// Check that a project can be generated into `dir` under an overwrite policy: unless the
+/// policy allows overwriting, `dir` must be missing, empty, or only hold a git repository
+pub fn check_target(dir: &Path, policy: OverwritePolicy) -> Result<()> {
+    if !dir.exists() || policy != OverwritePolicy::Fail {
+        return Ok(());
+    }
+    let occupied = std::fs::read_dir(dir)
+        .map_err(|e| Error::io_at(dir, e))?
+        .filter_map(|entry| entry.ok())
+        .any(|entry| entry.file_name() != ".git");
+    if occupied {
+        return Err(Error::InvalidPath(format!(
+            "Directory '{}' already exists and is not empty \
+             (use --force, --skip-existing or --prompt)",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Apply an overwrite policy to the files about to be written into `dir`
+pub fn resolve_existing<R: BufRead, W: Write>(
+    files: Vec<RenderedFile>,
+    dir: &Path,
+    policy: OverwritePolicy,
+    prompter: &mut Prompter<R, W>,
+) -> Result<Vec<RenderedFile>> {
+    check_target(dir, policy)?;
+    if !dir.exists() || policy == OverwritePolicy::Fail {
+        return Ok(files);
+    }
+
+    let mut kept = Vec::with_capacity(files.len());
+    for file in files {
+        let exists = dir.join(&file.path).exists();
+        let keep = match policy {
+            _ if !exists => true,
+            OverwritePolicy::Force | OverwritePolicy::Fail => true,
+            OverwritePolicy::SkipExisting => false,
+            OverwritePolicy::Prompt => {
+                prompter.confirm(&format!("Overwrite {}?", file.path.display()), false)?
+            }
+        };
+        if keep {
+            kept.push(file);
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Ask for the username, vendor and every declared variable that has no value yet
+pub fn prompt_missing<R: BufRead, W: Write>(
+    prompter: &mut Prompter<R, W>,
+    manifest: &Manifest,
+    username: &mut Option<String>,
+    vendor: &mut Option<String>,
+    values: &mut Values,
+) -> Result<()> {
+    let builtin = |name: &str, description: &str, default: &str| {
+        manifest
+            .variable(name)
+            .cloned()
+            .unwrap_or_else(|| Variable {
+                description: Some(description.to_string()),
+                default: Some(default.into()),
+                ..Variable::new(name)
+            })
+    };
+
+    if username.is_none() && !values.contains_key("repo_username") {
+        let variable = builtin(
+            "repo_username",
+            "Username for the project",
+            DEFAULT_USERNAME,
+        );
+        let value = prompter.ask_with(&variable, |value| {
+            validate_username(value.as_str().unwrap_or_default())
+        })?;
+        *username = value.as_str().map(str::to_string);
+    }
+
+    if vendor.is_none() && !values.contains_key("vendor") {
+        let variable = builtin("vendor", "Vendor name for the project", DEFAULT_VENDOR);
+        let value = prompter.ask(&variable)?;
+        *vendor = value.as_str().map(str::to_string);
+    }
+
+    for variable in &manifest.variables {
+        if BUILTIN_VARIABLES.contains(&variable.name.as_str())
+            || values.contains_key(&variable.name)
+        {
+            continue;
+        }
+        let value = prompter.ask(variable)?;
+        values.insert(variable.name.clone(), value);
+    }
+
+    Ok(())
+}
+
+/// Decide whether to run a template's hooks: always with `--trust`, after confirmation on a
+/// terminal, and never otherwise
+fn confirm_hooks(template: &Template, trust: bool, no_input: bool) -> Result<bool> {
+    let hooks = &template.manifest.hooks;
+    if hooks.is_empty() {
+        return Ok(false);
+    }
+    if trust {
+        return Ok(true);
+    }
+
+    if !no_input && std::io::stdin().is_terminal() {
+        eprintln!("Template '{}' wants to run these hooks:", template.name);
+        for stage in [HookStage::Pre, HookStage::Post] {
+            for hook in hooks.stage(stage) {
+                eprintln!("  {:<5} {}", stage.to_string(), hook);
+            }
+        }
+        return Prompter::terminal().confirm("Run them?", false);
+    }
+
+    eprintln!(
+        "Skipping the hooks of template '{}' (use --trust to run them)",
+        template.name
+    );
+    Ok(false)
+}
+
+/// List the template files left out by the manifest's rules
+fn print_skipped(skipped: &[PathBuf]) {
+    if skipped.is_empty() {
+        return;
+    }
+    println!("Skipped by template rules:");
+    for path in skipped {
+        println!("  - {}", path.display());
+    }
+}
+
+/// Every variable the layers of a template are rendered with: the context plus the defaults
+/// their manifests declare
+fn resolved_context(layers: &[Template], context: &TemplateContext) -> Result<serde_json::Value> {
+    let mut resolved = context.clone().into_context();
+    for layer in layers {
+        resolved = layer.manifest.resolve(resolved)?;
+    }
+    Ok(resolved.into_json())
+}
+
+/// Print an error on stderr: as JSON with `--json`, with the failing line of the template
+/// for diagnostics, and as a plain message otherwise
+pub fn report_error(error: &Error, json: bool) {
+    match error {
+        _ if json => eprintln!("{}", error.to_json()),
+        Error::Diagnostic(diagnostic) => eprint!("{}", diagnostic.render()),
+        _ => eprintln!("Error: {}", error),
+    }
+}
+
+/// List the files several layers of a composed template produced, and how they were combined
+fn print_conflicts(conflicts: &[Conflict]) {
+    if conflicts.is_empty() {
+        return;
+    }
+    println!("Files produced by more than one template:");
+    for conflict in conflicts {
+        println!("  - {}", conflict);
+    }
+}
+
+/// Load a template directory being worked on, or a template by name as `essex new` does
+fn load_template_or_dir(engine: &TemplateEngine, template: &str) -> Result<Template> {
+    let dir = Path::new(template);
+    if !dir.is_dir() {
+        return engine.load_template(template);
+    }
+    let name = dir
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::InvalidPath(template.to_string()))?;
+    Template::from_dir(&name, dir)
+}
+
+/// Archive name for a template directory: its name, plus the manifest version if it has one
+fn default_archive_name(dir: &Path) -> Result<PathBuf> {
+    let name = dir
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::InvalidPath(dir.display().to_string()))?;
+    let template = Template::from_dir(&name, dir)?;
+
+    Ok(PathBuf::from(match template.manifest.version {
+        Some(version) => format!("{}-{}.tar.gz", name, version),
+        None => format!("{}.tar.gz", name),
+    }))
+}
+
 #[derive(clap::ValueEnum, Clone)]
 pub enum Shell {
     Bash,
@@ -57,54 +409,360 @@ pub enum Shell {
 
 impl Cli {
     pub fn execute(self) -> Result<()> {
-        let mut engine = TemplateEngine::new(".")?;
+        let mut engine = match &self.templates_dir {
+            Some(dir) => TemplateEngine::from_templates_dir(dir)?,
+            None => TemplateEngine::builtin(),
+        };
+        if let Some(cache_dir) = &self.cache_dir {
+            engine = engine.with_cache_dir(cache_dir);
+        }
 
+        let format = self.format;
+        let text = format.is_text();
         match self.command {
             Commands::List => {
-                let templates = engine.list_templates()?;
-                println!("Available templates:");
-                for template in templates {
-                    println!("  - {}", template);
+                let templates = engine.templates()?;
+                if text {
+                    println!("Available templates:");
+                    for template in templates {
+                        println!("  - {} ({})", template.name, template.source);
+                    }
+                    return Ok(());
                 }
-                Ok(())
+
+                let summaries = templates
+                    .iter()
+                    .map(|t| Ok(TemplateSummary::new(&engine.load_template(&t.name)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                report::print(format, &summaries)
             }
             Commands::New {
                 template,
                 project,
-                username,
-                vendor,
+                mut username,
+                mut vendor,
+                set,
+                values_file,
+                no_input,
+                dry_run,
+                diff,
+                output,
+                in_place,
+                force,
+                skip_existing,
+                prompt,
+                trust,
+                with,
             } => {
-                // Validate template exists
-                let templates = engine.list_templates()?;
-                if !templates.contains(&template) {
-                    return Err(Error::TemplateNotFound(template));
+                // Validate the templates exist, fetching them first if they live in git
+                let names = compose::layer_names(&template, &with);
+                let layers = engine.load_layers(&names)?;
+                let template = names.join("+");
+
+                // Values from --set take precedence over those from --values
+                let mut values = match values_file {
+                    Some(path) => load_values_file(&path)?,
+                    None => Values::new(),
+                };
+                values.extend(set);
+
+                if !no_input && std::io::stdin().is_terminal() {
+                    for layer in &layers {
+                        prompt_missing(
+                            &mut Prompter::terminal(),
+                            &layer.manifest,
+                            &mut username,
+                            &mut vendor,
+                            &mut values,
+                        )?;
+                    }
                 }
 
-                let context = TemplateContext::new(&project, username, vendor)?;
-                let parts: Vec<&str> = project.split('/').collect();
-                if parts.len() != 2 {
-                    return Err(Error::InvalidProjectName(project));
+                let mut context =
+                    TemplateContext::new(&project, username, vendor)?.with_values(values);
+                context.validate()?;
+
+                // By default, create the project inside a directory named after the project,
+                // as checked by TemplateContext::new rather than as overridden by values
+                let project_dir = if in_place {
+                    PathBuf::from(".")
+                } else {
+                    output.unwrap_or_else(|| PathBuf::from(&project))
+                };
+
+                if dry_run {
+                    let rendered = engine.render_layers(&layers, context.clone())?;
+                    if !text {
+                        let report = NewReport {
+                            template,
+                            project,
+                            directory: project_dir,
+                            dry_run: true,
+                            files: rendered.files.iter().map(GeneratedFile::new).collect(),
+                            skipped: rendered.skipped,
+                            conflicts: rendered.conflicts,
+                            context: resolved_context(&layers, &context)?,
+                        };
+                        return report::print(format, &report);
+                    }
+                    print!("{}", preview::format_tree(&project_dir, &rendered.files));
+                    print_skipped(&rendered.skipped);
+                    print_conflicts(&rendered.conflicts);
+                    return Ok(());
                 }
 
-                // Create project directory inside a directory named after the namespace
-                let namespace_dir = PathBuf::from(parts[0]);
-                let project_dir = namespace_dir.join(parts[1]);
+                if let Some(diff_dir) = diff {
+                    let diff_dir = diff_dir.unwrap_or(project_dir);
+                    let rendered = engine.render_layers(&layers, context)?;
+                    if !text {
+                        let report = DiffReport {
+                            template,
+                            project,
+                            files: preview::diff_files(&diff_dir, &rendered.files)?,
+                            directory: diff_dir,
+                        };
+                        return report::print(format, &report);
+                    }
+                    print!("{}", preview::format_diff(&diff_dir, &rendered.files)?);
+                    return Ok(());
+                }
+
+                let policy = if force {
+                    OverwritePolicy::Force
+                } else if skip_existing {
+                    OverwritePolicy::SkipExisting
+                } else if prompt {
+                    // Without a terminal every question would read end of input, and so no
+                    if !std::io::stdin().is_terminal() {
+                        return Err(Error::InvalidValues(
+                            "--prompt needs a terminal to ask on; use --force or \
+                             --skip-existing instead"
+                                .to_string(),
+                        ));
+                    }
+                    OverwritePolicy::Prompt
+                } else {
+                    OverwritePolicy::Fail
+                };
+
+                // Refuse an occupied directory before any hook gets to run
+                check_target(&project_dir, policy)?;
 
-                if project_dir.exists() {
-                    return Err(Error::InvalidTemplate(format!(
-                        "Directory '{}' already exists",
-                        project_dir.display()
-                    )));
+                if text {
+                    println!(
+                        "Creating new project '{}' using template '{}'",
+                        project, template
+                    );
+                    for layer in &layers {
+                        if let TemplateSource::Git { url, commit } = &layer.source {
+                            println!("Using {} at commit {}", url, commit);
+                        }
+                    }
                 }
 
-                println!(
-                    "Creating new project '{}' using template '{}'",
-                    project, template
-                );
-                engine.generate(&template, context, &project_dir)?;
+                let mut trusted = Vec::with_capacity(layers.len());
+                for layer in &layers {
+                    trusted.push(confirm_hooks(layer, trust, no_input)?);
+                }
+                for (layer, _) in layers.iter().zip(&trusted).filter(|(_, run)| **run) {
+                    if !layer.manifest.hooks.pre.is_empty() {
+                        let resolved = layer.manifest.resolve(context.clone().into_context())?;
+                        let env = hook_env(&resolved, &project_dir);
+                        let values = hooks::run_hooks(layer, HookStage::Pre, &env, Path::new("."))?;
+                        context = context.with_values(values);
+                        context.validate()?;
+                    }
+                }
+
+                let Rendered {
+                    mut files,
+                    skipped,
+                    copied,
+                    dir_modes,
+                    conflicts,
+                } = engine.render_layers(&layers, context.clone())?;
+                let mut answers = Answers::new(&template, &context, &files, &copied);
+                answers.commit = layers
+                    .iter()
+                    .find_map(|layer| layer.source.commit())
+                    .map(str::to_string);
+                files.push(answers.to_file()?);
+
+                let files =
+                    resolve_existing(files, &project_dir, policy, &mut Prompter::terminal())?;
+                write_project(&files, &project_dir)?;
+                set_dir_modes(&dir_modes, &project_dir)?;
+                if text {
+                    print_skipped(&skipped);
+                    print_conflicts(&conflicts);
+                }
+
+                for (layer, _) in layers.iter().zip(&trusted).filter(|(_, run)| **run) {
+                    if !layer.manifest.hooks.post.is_empty() {
+                        let resolved = layer.manifest.resolve(context.clone().into_context())?;
+                        let env = hook_env(&resolved, &project_dir);
+                        hooks::run_hooks(layer, HookStage::Post, &env, &project_dir)?;
+                    }
+                }
+                if !text {
+                    let report = NewReport {
+                        template,
+                        project,
+                        dry_run: false,
+                        files: files.iter().map(GeneratedFile::new).collect(),
+                        skipped,
+                        conflicts,
+                        context: resolved_context(&layers, &context)?,
+                        directory: project_dir,
+                    };
+                    return report::print(format, &report);
+                }
                 println!("Project created successfully!");
                 Ok(())
             }
+            Commands::Info { template } => {
+                let template = engine.load_template(&template)?;
+                let report = InfoReport::new(&template, engine.inspect(&template)?);
+                if text {
+                    print!("{}", report.to_text());
+                    return Ok(());
+                }
+                report::print(format, &report)
+            }
+            Commands::Update { path, set } => {
+                let answers = Answers::load(&path)?;
+                if text {
+                    println!(
+                        "Updating '{}' from template '{}' (generated with essex {})",
+                        path.display(),
+                        answers.template,
+                        answers.essex_version
+                    );
+                }
+
+                let context = answers.context.clone().with_values(set);
+                let layers = engine.load_layers(&compose::layer_names(&answers.template, &[]))?;
+                let rendered = engine.render_layers(&layers, context.clone())?;
+                let (writes, report) = merge_update(&path, &answers, &rendered.files)?;
+                write_files(&writes, &path)?;
+
+                let mut updated = Answers::new(
+                    &answers.template,
+                    &context,
+                    &rendered.files,
+                    &rendered.copied,
+                );
+                updated.commit = layers
+                    .iter()
+                    .find_map(|layer| layer.source.commit())
+                    .map(str::to_string);
+                write_files(&[updated.to_file()?], &path)?;
+
+                if !text {
+                    let report = UpdateReport {
+                        directory: path,
+                        template: answers.template,
+                        files: report
+                            .into_iter()
+                            .map(|(path, status)| UpdatedFile { path, status })
+                            .collect(),
+                    };
+                    return report::print(format, &report);
+                }
+                if report.is_empty() {
+                    println!("Project is up to date.");
+                }
+                for (file, status) in &report {
+                    println!("  {:<9} {}", status.to_string(), file.display());
+                }
+
+                let conflicts = report
+                    .iter()
+                    .filter(|(_, status)| *status == UpdateStatus::Conflict)
+                    .count();
+                if conflicts > 0 {
+                    println!(
+                        "Resolve the conflicts in {} file(s) before committing: text files \
+                         have conflict markers, binary and copied files were left as they are.",
+                        conflicts
+                    );
+                }
+                Ok(())
+            }
+            Commands::Template { command } => match command {
+                TemplateCommands::Pack { dir, output } => {
+                    let output = match output {
+                        Some(output) => output,
+                        None => default_archive_name(&dir)?,
+                    };
+                    let checksum = archive::pack(&dir, &output)?;
+                    if !text {
+                        let report = PackReport {
+                            template: dir,
+                            archive: output,
+                            sha256: checksum,
+                        };
+                        return report::print(format, &report);
+                    }
+                    println!("Packed '{}' into {}", dir.display(), output.display());
+                    println!("sha256: {}", checksum);
+                    Ok(())
+                }
+                TemplateCommands::Lint { template } => {
+                    let template = load_template_or_dir(&engine, &template)?;
+                    let report = LintReport::new(&template, engine.lint(&template)?);
+                    if text {
+                        print!("{}", report.to_text());
+                    } else {
+                        report::print(format, &report)?;
+                    }
+                    match report.errors {
+                        0 => Ok(()),
+                        errors => Err(Error::InvalidTemplate(format!(
+                            "{}: {} error(s) found",
+                            report.template, errors
+                        ))),
+                    }
+                }
+                TemplateCommands::Test { template, bless } => {
+                    let template = load_template_or_dir(&engine, &template)?;
+                    let dir = match (&template.source, bless) {
+                        (_, false) => None,
+                        (TemplateSource::Local(dir), true) => Some(dir),
+                        (source, true) => {
+                            return Err(Error::InvalidTemplate(format!(
+                                "cannot bless '{}', which is loaded from {} rather than a \
+                                 template directory",
+                                template.name, source
+                            )))
+                        }
+                    };
+
+                    let mut results = engine.test(&template)?;
+                    if let Some(dir) = dir {
+                        for result in &mut results {
+                            if result.status == CaseStatus::Failed && result.error.is_none() {
+                                snapshot::bless(dir, &result.name, &result.rendered)?;
+                                result.status = CaseStatus::Blessed;
+                            }
+                        }
+                    }
+
+                    let report = TestReport::new(&template, results);
+                    if text {
+                        print!("{}", report.to_text());
+                    } else {
+                        report::print(format, &report)?;
+                    }
+                    match report.failed {
+                        0 => Ok(()),
+                        failed => Err(Error::InvalidTemplate(format!(
+                            "{}: {} test case(s) failed",
+                            report.template, failed
+                        ))),
+                    }
+                }
+            },
             Commands::Completion { shell, output } => {
                 let mut cmd = Cli::command();
                 let bin_name = cmd.get_name().to_string();
@@ -152,6 +810,56 @@ mod tests {
         assert!(matches!(cli.command, Commands::List));
     }
 
+    #[test]
+    fn test_format_parsing() {
+        let cli = Cli::try_parse_from(["essex", "list"]).unwrap();
+        assert_eq!(cli.format, Format::Text);
+
+        let cli = Cli::try_parse_from(["essex", "list", "--format", "yaml"]).unwrap();
+        assert_eq!(cli.format, Format::Yaml);
+
+        let cli =
+            Cli::try_parse_from(["essex", "--format", "json", "new", "basic", "a/b"]).unwrap();
+        assert_eq!(cli.format, Format::Json);
+
+        assert!(Cli::try_parse_from(["essex", "list", "--format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_info_command_parsing() {
+        let cli = Cli::try_parse_from(["essex", "info", "basic"]).unwrap();
+        match cli.command {
+            Commands::Info { template } => assert_eq!(template, "basic"),
+            _ => panic!("Expected Info command"),
+        }
+        assert!(Cli::try_parse_from(["essex", "info"]).is_err());
+    }
+
+    #[test]
+    fn test_template_lint_command_parsing() {
+        let cli = Cli::try_parse_from(["essex", "template", "lint", "./svc"]).unwrap();
+        match cli.command {
+            Commands::Template {
+                command: TemplateCommands::Lint { template },
+            } => assert_eq!(template, "./svc"),
+            _ => panic!("Expected Template Lint command"),
+        }
+    }
+
+    #[test]
+    fn test_template_test_command_parsing() {
+        let cli = Cli::try_parse_from(["essex", "template", "test", "./svc", "--bless"]).unwrap();
+        match cli.command {
+            Commands::Template {
+                command: TemplateCommands::Test { template, bless },
+            } => {
+                assert_eq!(template, "./svc");
+                assert!(bless);
+            }
+            _ => panic!("Expected Template Test command"),
+        }
+    }
+
     #[test]
     fn test_new_command_parsing() {
         // Test basic new command
@@ -162,11 +870,33 @@ mod tests {
                 project,
                 username,
                 vendor,
+                set,
+                values_file,
+                no_input,
+                dry_run,
+                diff,
+                output,
+                in_place,
+                force,
+                skip_existing,
+                prompt,
+                trust,
+                with,
             } => {
                 assert_eq!(template, "basic");
                 assert_eq!(project, "test/project");
                 assert!(username.is_none());
                 assert!(vendor.is_none());
+                assert!(set.is_empty());
+                assert!(values_file.is_none());
+                assert!(!no_input);
+                assert!(!dry_run);
+                assert!(diff.is_none());
+                assert!(output.is_none());
+                assert!(!in_place);
+                assert!(!force && !skip_existing && !prompt);
+                assert!(!trust);
+                assert!(with.is_empty());
             }
             _ => panic!("Expected New command"),
         }
@@ -189,6 +919,7 @@ mod tests {
                 project,
                 username,
                 vendor,
+                ..
             } => {
                 assert_eq!(template, "basic");
                 assert_eq!(project, "test/project");
@@ -199,6 +930,280 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_new_command_with_parsing() {
+        let cli = Cli::try_parse_from([
+            "essex",
+            "new",
+            "basic+compose",
+            "test/project",
+            "--with",
+            "github-actions",
+            "--with",
+            "gh:acme/templates//ci",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::New { template, with, .. } => {
+                assert_eq!(
+                    compose::layer_names(&template, &with),
+                    vec![
+                        "basic",
+                        "compose",
+                        "github-actions",
+                        "gh:acme/templates//ci"
+                    ]
+                );
+            }
+            _ => panic!("Expected New command"),
+        }
+    }
+
+    #[test]
+    fn test_new_command_values_parsing() {
+        let cli = Cli::try_parse_from([
+            "essex",
+            "new",
+            "basic",
+            "test/project",
+            "--set",
+            "version=1.2.3",
+            "--set",
+            "vcs_ref=abc123",
+            "--values",
+            "answers.yaml",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::New {
+                set, values_file, ..
+            } => {
+                assert_eq!(
+                    set,
+                    vec![
+                        ("version".to_string(), serde_json::json!("1.2.3")),
+                        ("vcs_ref".to_string(), serde_json::json!("abc123")),
+                    ]
+                );
+                assert_eq!(values_file.unwrap(), PathBuf::from("answers.yaml"));
+            }
+            _ => panic!("Expected New command"),
+        }
+
+        assert!(
+            Cli::try_parse_from(["essex", "new", "basic", "test/project", "--set", "x"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_new_command_preview_parsing() {
+        let cli = Cli::try_parse_from(["essex", "new", "basic", "a/b", "--dry-run"]).unwrap();
+        assert!(matches!(cli.command, Commands::New { dry_run: true, .. }));
+
+        let cli = Cli::try_parse_from(["essex", "new", "basic", "a/b", "--diff"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::New {
+                diff: Some(None),
+                ..
+            }
+        ));
+
+        let cli = Cli::try_parse_from(["essex", "new", "basic", "a/b", "--diff", "other"]).unwrap();
+        match cli.command {
+            Commands::New { diff, .. } => assert_eq!(diff, Some(Some(PathBuf::from("other")))),
+            _ => panic!("Expected New command"),
+        }
+
+        assert!(
+            Cli::try_parse_from(["essex", "new", "basic", "a/b", "--dry-run", "--diff"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_new_command_output_parsing() {
+        let cli =
+            Cli::try_parse_from(["essex", "new", "basic", "a/b", "-o", "out", "--force"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::New {
+                output: Some(_),
+                force: true,
+                ..
+            }
+        ));
+
+        assert!(
+            Cli::try_parse_from(["essex", "new", "basic", "a/b", "-o", "x", "--in-place"]).is_err()
+        );
+        assert!(Cli::try_parse_from([
+            "essex",
+            "new",
+            "basic",
+            "a/b",
+            "--force",
+            "--skip-existing"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_resolve_existing() -> Result<()> {
+        let dir = tempdir()?;
+        let file = |path: &str| RenderedFile {
+            path: PathBuf::from(path),
+            contents: b"new".to_vec(),
+            mode: 0o644,
+        };
+        let files = vec![file("README.md"), file("Makefile"), file("Dockerfile")];
+        let mut output = Vec::new();
+        let mut prompter = Prompter::new(std::io::Cursor::new("y\nn\n"), &mut output);
+
+        // Missing, empty and git-only directories are fine
+        let missing = dir.path().join("missing");
+        let kept = resolve_existing(
+            files.clone(),
+            &missing,
+            OverwritePolicy::Fail,
+            &mut prompter,
+        )?;
+        assert_eq!(kept.len(), 3);
+        fs::create_dir_all(dir.path().join(".git"))?;
+        let kept = resolve_existing(
+            files.clone(),
+            dir.path(),
+            OverwritePolicy::Fail,
+            &mut prompter,
+        )?;
+        assert_eq!(kept.len(), 3);
+
+        fs::write(dir.path().join("README.md"), "old")?;
+        fs::write(dir.path().join("Makefile"), "old")?;
+        assert!(resolve_existing(
+            files.clone(),
+            dir.path(),
+            OverwritePolicy::Fail,
+            &mut prompter
+        )
+        .is_err());
+
+        let kept = resolve_existing(
+            files.clone(),
+            dir.path(),
+            OverwritePolicy::Force,
+            &mut prompter,
+        )?;
+        assert_eq!(kept.len(), 3);
+
+        let kept = resolve_existing(
+            files.clone(),
+            dir.path(),
+            OverwritePolicy::SkipExisting,
+            &mut prompter,
+        )?;
+        let paths: Vec<_> = kept.iter().map(|f| f.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["Dockerfile"]);
+
+        let kept = resolve_existing(files, dir.path(), OverwritePolicy::Prompt, &mut prompter)?;
+        let paths: Vec<_> = kept.iter().map(|f| f.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["README.md", "Dockerfile"]);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Overwrite Makefile? [y/N]: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_missing() {
+        let manifest = Manifest::parse(
+            "[[variables]]\nname = \"author_email\"\ndefault = \"contact@example.com\"\n\n\
+             [[variables]]\nname = \"port\"\ntype = \"integer\"\n\n\
+             [[variables]]\nname = \"version\"\ndefault = \"0.1.0\"\n",
+        )
+        .unwrap();
+
+        let mut username = None;
+        let mut vendor = Some("Acme".to_string());
+        let mut values = Values::new();
+        values.insert("author_email".to_string(), "ops@acme.io".into());
+
+        let mut output = Vec::new();
+        let mut prompter = Prompter::new(
+            std::io::Cursor::new(
+                "bad user
+acme-ci
+9000
+",
+            ),
+            &mut output,
+        );
+        prompt_missing(
+            &mut prompter,
+            &manifest,
+            &mut username,
+            &mut vendor,
+            &mut values,
+        )
+        .unwrap();
+
+        assert_eq!(username.as_deref(), Some("acme-ci"));
+        assert_eq!(vendor.as_deref(), Some("Acme"));
+        assert_eq!(values["port"], serde_json::json!(9000));
+        assert_eq!(values["author_email"], serde_json::json!("ops@acme.io"));
+        assert!(!values.contains_key("version"));
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("repo_username [example]: "));
+        assert!(output.contains("only alphanumeric characters"));
+        assert!(!output.contains("vendor"));
+    }
+
+    #[test]
+    fn test_update_command_parsing() {
+        let cli = Cli::try_parse_from(["essex", "update"]).unwrap();
+        match cli.command {
+            Commands::Update { path, set } => {
+                assert_eq!(path, PathBuf::from("."));
+                assert!(set.is_empty());
+            }
+            _ => panic!("Expected Update command"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["essex", "update", "acme/app", "--set", "version=2.0.0"]).unwrap();
+        match cli.command {
+            Commands::Update { path, set } => {
+                assert_eq!(path, PathBuf::from("acme/app"));
+                assert_eq!(set.len(), 1);
+            }
+            _ => panic!("Expected Update command"),
+        }
+    }
+
+    #[test]
+    fn test_template_pack_command_parsing() {
+        let cli = Cli::try_parse_from(["essex", "template", "pack", "templates/python"]).unwrap();
+        match cli.command {
+            Commands::Template {
+                command: TemplateCommands::Pack { dir, output },
+            } => {
+                assert_eq!(dir, PathBuf::from("templates/python"));
+                assert!(output.is_none());
+            }
+            _ => panic!("Expected Template Pack command"),
+        }
+
+        let cli = Cli::try_parse_from(["essex", "template", "pack", "python", "-o", "python.zip"])
+            .unwrap();
+        match cli.command {
+            Commands::Template {
+                command: TemplateCommands::Pack { output, .. },
+            } => assert_eq!(output, Some(PathBuf::from("python.zip"))),
+            _ => panic!("Expected Template Pack command"),
+        }
+    }
+
     #[test]
     fn test_completion_command_parsing() {
         // Test bash completion
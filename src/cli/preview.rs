@@ -0,0 +1,245 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::template::RenderedFile;
+
+enum Node<'a> {
+    Dir(BTreeMap<String, Node<'a>>),
+    File(&'a RenderedFile),
+}
+
+/// Format rendered files as a tree under `root`, with the mode and size of each file
+pub fn format_tree(root: &Path, files: &[RenderedFile]) -> String {
+    let mut tree = BTreeMap::new();
+    for file in files {
+        let components: Vec<String> = file
+            .path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        insert(&mut tree, &components, file);
+    }
+
+    let mut output = format!("{}/\n", root.display());
+    write_tree(&mut output, &tree, "");
+    output
+}
+
+fn insert<'a>(
+    tree: &mut BTreeMap<String, Node<'a>>,
+    components: &[String],
+    file: &'a RenderedFile,
+) {
+    match components {
+        [] => {}
+        [name] => {
+            tree.insert(name.clone(), Node::File(file));
+        }
+        [dir, rest @ ..] => {
+            let node = tree
+                .entry(dir.clone())
+                .or_insert_with(|| Node::Dir(BTreeMap::new()));
+            if let Node::Dir(children) = node {
+                insert(children, rest, file);
+            }
+        }
+    }
+}
+
+fn write_tree(output: &mut String, tree: &BTreeMap<String, Node>, prefix: &str) {
+    for (index, (name, node)) in tree.iter().enumerate() {
+        let last = index == tree.len() - 1;
+        let branch = if last { "└── " } else { "├── " };
+        match node {
+            Node::Dir(children) => {
+                let _ = writeln!(output, "{}{}{}/", prefix, branch, name);
+                let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                write_tree(output, children, &child_prefix);
+            }
+            Node::File(file) => {
+                let _ = writeln!(
+                    output,
+                    "{}{}{} ({:04o}, {} bytes)",
+                    prefix,
+                    branch,
+                    name,
+                    file.mode,
+                    file.contents.len()
+                );
+            }
+        }
+    }
+}
+
+/// How a rendered file compares to the one already in the directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    /// Not in the directory yet
+    Added,
+    /// In the directory with different contents or mode
+    Modified,
+    Unchanged,
+}
+
+/// A rendered file compared to the one already in the directory
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    pub status: DiffStatus,
+    /// Unified diff from the existing file to the rendered one, with any mode change;
+    /// empty when unchanged
+    pub diff: String,
+}
+
+/// Compare each rendered file to the file at the same path in `dir`
+pub fn diff_files(dir: &Path, files: &[RenderedFile]) -> Result<Vec<FileDiff>> {
+    files.iter().map(|file| diff_file(dir, file)).collect()
+}
+
+fn diff_file(dir: &Path, file: &RenderedFile) -> Result<FileDiff> {
+    let mut output = String::new();
+    let existing_path = dir.join(&file.path);
+    let existing = if existing_path.is_file() {
+        Some(std::fs::read(&existing_path)?)
+    } else {
+        None
+    };
+
+    let old_name = match existing {
+        Some(_) => format!("a/{}", file.path.display()),
+        None => "/dev/null".to_string(),
+    };
+    let new_name = format!("b/{}", file.path.display());
+
+    #[cfg(unix)]
+    if existing.is_some() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&existing_path)?.permissions().mode() & 0o7777;
+        if mode != file.mode {
+            let _ = writeln!(output, "diff {} {}", old_name, new_name);
+            let _ = writeln!(output, "old mode {:04o}", mode);
+            let _ = writeln!(output, "new mode {:04o}", file.mode);
+        }
+    }
+
+    let original = existing.as_deref().unwrap_or_default();
+    if original != file.contents.as_slice() {
+        match (
+            std::str::from_utf8(original),
+            std::str::from_utf8(&file.contents),
+        ) {
+            (Ok(original), Ok(modified)) => {
+                let patch = diffy::DiffOptions::new()
+                    .set_original_filename(old_name)
+                    .set_modified_filename(new_name)
+                    .create_patch(original, modified);
+                output.push_str(&patch.to_string());
+            }
+            _ => {
+                let _ = writeln!(output, "Binary files {} and {} differ", old_name, new_name);
+            }
+        }
+    }
+
+    let status = match existing {
+        None => DiffStatus::Added,
+        Some(_) if output.is_empty() => DiffStatus::Unchanged,
+        Some(_) => DiffStatus::Modified,
+    };
+    Ok(FileDiff {
+        path: file.path.clone(),
+        status,
+        diff: output,
+    })
+}
+
+/// Format a unified diff between the files in `dir` and the rendered files
+pub fn format_diff(dir: &Path, files: &[RenderedFile]) -> Result<String> {
+    Ok(diff_files(dir, files)?
+        .into_iter()
+        .map(|file| file.diff)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn rendered(path: &str, contents: &str, mode: u32) -> RenderedFile {
+        RenderedFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn test_format_tree() {
+        let files = vec![
+            rendered("Dockerfile", "FROM alpine\n", 0o644),
+            rendered("bin/entrypoint.sh", "#!/bin/sh\n", 0o755),
+            rendered("bin/lib/util.sh", "", 0o755),
+            rendered("README.md", "# app\n", 0o644),
+        ];
+
+        let tree = format_tree(Path::new("acme/app"), &files);
+        assert_eq!(
+            tree,
+            "acme/app/\n\
+             ├── Dockerfile (0644, 12 bytes)\n\
+             ├── README.md (0644, 6 bytes)\n\
+             └── bin/\n\
+            \x20   ├── entrypoint.sh (0755, 10 bytes)\n\
+            \x20   └── lib/\n\
+            \x20       └── util.sh (0755, 0 bytes)\n"
+        );
+    }
+
+    #[test]
+    fn test_format_diff() -> Result<()> {
+        let dir = tempdir()?;
+        std::fs::write(dir.path().join("README.md"), "# app\nold line\n")?;
+        std::fs::write(dir.path().join("same.txt"), "same\n")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for name in ["README.md", "same.txt"] {
+                let path = dir.path().join(name);
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+            }
+        }
+
+        let files = vec![
+            rendered("README.md", "# app\nnew line\n", 0o644),
+            rendered("same.txt", "same\n", 0o644),
+            rendered("new.txt", "hello\n", 0o644),
+        ];
+
+        let diff = format_diff(dir.path(), &files)?;
+        assert!(diff.contains("--- a/README.md\n+++ b/README.md\n"));
+        assert!(diff.contains("-old line\n+new line\n"));
+        assert!(diff.contains("--- /dev/null\n+++ b/new.txt\n"));
+        assert!(!diff.contains("same.txt"));
+
+        assert!(format_diff(dir.path(), &files[1..2])?.is_empty());
+
+        let statuses: Vec<DiffStatus> = diff_files(dir.path(), &files)?
+            .iter()
+            .map(|file| file.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                DiffStatus::Modified,
+                DiffStatus::Unchanged,
+                DiffStatus::Added
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+use crate::error::{Error, Result};
+use crate::template::manifest::display_value;
+use crate::template::Variable;
+
+/// Asks for variable values on an input/output pair.
+///
+/// The terminal prompter reads stdin and writes to stderr; tests can script the answers by
+/// handing in any reader and writer.
+pub struct Prompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl Prompter<std::io::StdinLock<'static>, std::io::Stderr> {
+    pub fn terminal() -> Self {
+        Self::new(std::io::stdin().lock(), std::io::stderr())
+    }
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Ask a yes/no question, returning `default` on an empty answer
+    pub fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            write!(self.output, "{} [{}]: ", question, hint)?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(default);
+            }
+
+            match line.trim().to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "  Please answer y or n")?,
+            }
+        }
+    }
+
+    /// Ask for a value for `variable`, repeating the question until the answer is valid
+    pub fn ask(&mut self, variable: &Variable) -> Result<Value> {
+        self.ask_with(variable, |_| Ok(()))
+    }
+
+    /// Like [`Prompter::ask`], with an extra check run on the converted value
+    pub fn ask_with<F>(&mut self, variable: &Variable, check: F) -> Result<Value>
+    where
+        F: Fn(&Value) -> Result<()>,
+    {
+        if let Some(description) = &variable.description {
+            writeln!(self.output, "{}", description)?;
+        }
+        for (index, choice) in variable.choices.iter().enumerate() {
+            writeln!(self.output, "  {}) {}", index + 1, display_value(choice))?;
+        }
+
+        loop {
+            match &variable.default {
+                Some(default) => write!(
+                    self.output,
+                    "{} [{}]: ",
+                    variable.name,
+                    display_value(default)
+                )?,
+                None => write!(self.output, "{}: ", variable.name)?,
+            }
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(Error::MissingVariable(variable.name.clone()));
+            }
+
+            let answer = line.trim();
+            let value = if answer.is_empty() {
+                match &variable.default {
+                    Some(default) => default.clone(),
+                    None => {
+                        writeln!(self.output, "  A value is required")?;
+                        continue;
+                    }
+                }
+            } else {
+                choice_by_number(variable, answer).unwrap_or_else(|| Value::from(answer))
+            };
+
+            match variable.validate(&value).and_then(|v| check(&v).map(|_| v)) {
+                Ok(value) => return Ok(value),
+                Err(e) => writeln!(self.output, "  {}", e)?,
+            }
+        }
+    }
+}
+
+/// Map an answer like `2` to the second choice, unless the answer is itself a choice
+fn choice_by_number(variable: &Variable, answer: &str) -> Option<Value> {
+    if variable
+        .choices
+        .iter()
+        .any(|choice| display_value(choice) == answer)
+    {
+        return None;
+    }
+
+    let index = answer.parse::<usize>().ok()?.checked_sub(1)?;
+    variable.choices.get(index).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::VariableType;
+    use serde_json::json;
+    use std::io::Cursor;
+
+    fn ask(variable: &Variable, answers: &str) -> (Result<Value>, String) {
+        let mut output = Vec::new();
+        let result = Prompter::new(Cursor::new(answers), &mut output).ask(variable);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_prompt_default() {
+        let mut port = Variable::new("port");
+        port.kind = VariableType::Integer;
+        port.default = Some(json!(8080));
+        port.description = Some("Port to expose".to_string());
+
+        let (result, output) = ask(&port, "\n");
+        assert_eq!(result.unwrap(), json!(8080));
+        assert_eq!(output, "Port to expose\nport [8080]: ");
+    }
+
+    #[test]
+    fn test_prompt_retries_invalid_answers() {
+        let mut team = Variable::new("team");
+        team.pattern = Some("[a-z]+".to_string());
+
+        let (result, output) = ask(&team, "\nPlatform Team\nplatform\n");
+        assert_eq!(result.unwrap(), json!("platform"));
+        assert!(output.contains("A value is required"));
+        assert!(output.contains("does not match pattern"));
+    }
+
+    #[test]
+    fn test_prompt_choices() {
+        let mut python = Variable::new("python_version");
+        python.choices = vec![json!("3.11"), json!("3.12")];
+
+        let (result, output) = ask(&python, "2\n");
+        assert_eq!(result.unwrap(), json!("3.12"));
+        assert!(output.contains("  1) 3.11\n  2) 3.12\n"));
+
+        let (result, _) = ask(&python, "3.11\n");
+        assert_eq!(result.unwrap(), json!("3.11"));
+
+        let (result, output) = ask(&python, "3\n3.12\n");
+        assert_eq!(result.unwrap(), json!("3.12"));
+        assert!(output.contains("'3' is not one of: 3.11, 3.12"));
+    }
+
+    #[test]
+    fn test_confirm() {
+        let mut output = Vec::new();
+        let mut prompter = Prompter::new(Cursor::new("maybe\ny\n\n"), &mut output);
+        assert!(prompter.confirm("Overwrite?", false).unwrap());
+        assert!(!prompter.confirm("Overwrite?", false).unwrap());
+        assert!(prompter.confirm("Overwrite?", true).unwrap());
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Overwrite? [y/N]: "));
+        assert!(output.contains("Please answer y or n"));
+    }
+
+    #[test]
+    fn test_prompt_extra_check_and_eof() {
+        let username = Variable::new("repo_username");
+        let mut output = Vec::new();
+        let result = Prompter::new(Cursor::new("bad name\ngood\n"), &mut output).ask_with(
+            &username,
+            |value| match value.as_str() {
+                Some(s) if !s.contains(' ') => Ok(()),
+                _ => Err(Error::InvalidVariable("no spaces".to_string())),
+            },
+        );
+        assert_eq!(result.unwrap(), json!("good"));
+        assert!(String::from_utf8(output).unwrap().contains("no spaces"));
+
+        let (result, _) = ask(&username, "");
+        assert!(matches!(result, Err(Error::MissingVariable(name)) if name == "repo_username"));
+    }
+}
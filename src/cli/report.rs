@@ -0,0 +1,358 @@
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+use super::preview::FileDiff;
+use crate::error::{Error, Result};
+use crate::template::archive::to_hex;
+use crate::template::manifest::display_value;
+use crate::template::{
+    CaseResult, CaseStatus, Conflict, Finding, InspectedFile, InspectedVariable, Inspection,
+    MismatchKind, RenderedFile, Severity, Template, UpdateStatus, Variable,
+};
+
+/// How commands print their results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Human-readable text
+    #[default]
+    Text,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn is_text(self) -> bool {
+        self == Format::Text
+    }
+}
+
+/// Print a report on stdout as JSON or YAML. Text output is printed by each command.
+pub fn print<T: Serialize>(format: Format, report: &T) -> Result<()> {
+    match format {
+        Format::Text => {}
+        Format::Json => {
+            let json =
+                serde_json::to_string_pretty(report).map_err(|e| Error::IoError(e.into()))?;
+            println!("{}", json);
+        }
+        Format::Yaml => {
+            let yaml = serde_yaml::to_string(report)
+                .map_err(|e| Error::IoError(std::io::Error::other(e)))?;
+            print!("{}", yaml);
+        }
+    }
+    Ok(())
+}
+
+/// A template, as listed by `essex list`
+#[derive(Debug, Serialize)]
+pub struct TemplateSummary {
+    pub name: String,
+    pub source: String,
+    pub description: Option<String>,
+    pub version: Option<String>,
+    pub variables: Vec<Variable>,
+}
+
+impl TemplateSummary {
+    pub fn new(template: &Template) -> Self {
+        Self {
+            name: template.name.clone(),
+            source: template.source.to_string(),
+            description: template.manifest.description.clone(),
+            version: template.manifest.version.clone(),
+            variables: template.manifest.variables.clone(),
+        }
+    }
+}
+
+/// A template's inputs and outputs, as described by `essex info`
+#[derive(Debug, Serialize)]
+pub struct InfoReport {
+    pub name: String,
+    pub source: String,
+    pub description: Option<String>,
+    pub version: Option<String>,
+    pub variables: Vec<InspectedVariable>,
+    pub files: Vec<InspectedFile>,
+}
+
+impl InfoReport {
+    pub fn new(template: &Template, inspection: Inspection) -> Self {
+        Self {
+            name: template.name.clone(),
+            source: template.source.to_string(),
+            description: template.manifest.description.clone(),
+            version: template.manifest.version.clone(),
+            variables: inspection.variables,
+            files: inspection.files,
+        }
+    }
+
+    /// The report as text, with one line per variable and per file
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{} ({})\n", self.name, self.source);
+        if let Some(description) = &self.description {
+            out.push_str(&format!("{}\n", description));
+        }
+        if let Some(version) = &self.version {
+            out.push_str(&format!("Version: {}\n", version));
+        }
+
+        out.push_str("\nVariables:\n");
+        let defaults: Vec<String> = self
+            .variables
+            .iter()
+            .map(|variable| match &variable.default {
+                Some(default) => format!("default {}", display_value(default)),
+                None if variable.builtin => "built-in".to_string(),
+                None => "required".to_string(),
+            })
+            .collect();
+        let width = self
+            .variables
+            .iter()
+            .map(|v| v.name.len())
+            .max()
+            .unwrap_or(0);
+        let default_width = defaults.iter().map(String::len).max().unwrap_or(0);
+        for (variable, default) in self.variables.iter().zip(&defaults) {
+            let mut line = format!("  {:<width$}  {:<default_width$}", variable.name, default);
+            if let Some(description) = &variable.description {
+                line.push_str(&format!("  {}", description));
+            }
+            if !variable.used {
+                line.push_str("  (unused)");
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+
+        out.push_str("\nFiles:\n");
+        let paths: Vec<String> = self
+            .files
+            .iter()
+            .map(|f| f.path.display().to_string())
+            .collect();
+        let width = paths.iter().map(String::len).max().unwrap_or(0);
+        for (file, path) in self.files.iter().zip(&paths) {
+            let mut line = format!("  {:<width$}  {}", path, file.mode);
+            if file.executable {
+                line.push_str("  executable");
+            }
+            if !file.rendered {
+                line.push_str("  copied");
+            }
+            if !file.conditions.is_empty() {
+                line.push_str(&format!("  if {}", file.conditions.join(" and ")));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Problems found by `essex template lint`
+#[derive(Debug, Serialize)]
+pub struct LintReport {
+    pub template: String,
+    pub errors: usize,
+    pub warnings: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl LintReport {
+    pub fn new(template: &Template, findings: Vec<Finding>) -> Self {
+        let errors = findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .count();
+        Self {
+            template: template.name.clone(),
+            errors,
+            warnings: findings.len() - errors,
+            findings,
+        }
+    }
+
+    /// Each finding with its offending line, then the counts
+    pub fn to_text(&self) -> String {
+        if self.findings.is_empty() {
+            return format!("No problems found in '{}'\n", self.template);
+        }
+        let mut out = String::new();
+        for finding in &self.findings {
+            out.push_str(&finding.render());
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "'{}': {} error(s), {} warning(s)\n",
+            self.template, self.errors, self.warnings
+        ));
+        out
+    }
+}
+
+/// Test cases run by `essex template test`
+#[derive(Debug, Serialize)]
+pub struct TestReport {
+    pub template: String,
+    pub passed: usize,
+    pub failed: usize,
+    pub blessed: usize,
+    pub cases: Vec<CaseResult>,
+}
+
+impl TestReport {
+    pub fn new(template: &Template, cases: Vec<CaseResult>) -> Self {
+        let count = |status| cases.iter().filter(|c| c.status == status).count();
+        Self {
+            template: template.name.clone(),
+            passed: count(CaseStatus::Passed),
+            failed: count(CaseStatus::Failed),
+            blessed: count(CaseStatus::Blessed),
+            cases,
+        }
+    }
+
+    /// A line per case, then the error or diff of each failing case, then the counts
+    pub fn to_text(&self) -> String {
+        if self.cases.is_empty() {
+            return format!(
+                "No test cases found in '{}': add tests/<case>/answers.yaml and run with \
+                 --bless to record its expected files\n",
+                self.template
+            );
+        }
+
+        let mut out = String::new();
+        for case in &self.cases {
+            let status = match case.status {
+                CaseStatus::Passed => "ok".to_string(),
+                CaseStatus::Failed => "FAILED".to_string(),
+                CaseStatus::Blessed => format!("blessed ({} file(s))", case.mismatches.len()),
+            };
+            out.push_str(&format!("test {} ... {}\n", case.name, status));
+        }
+
+        for case in self.cases.iter().filter(|c| c.status == CaseStatus::Failed) {
+            out.push_str(&format!("\n---- {} ----\n", case.name));
+            if let Some(error) = &case.error {
+                out.push_str(&format!("{}\n", error));
+            }
+            // Whole files that are missing or unexpected are named rather than printed
+            for mismatch in &case.mismatches {
+                match mismatch.kind {
+                    MismatchKind::Missing => {
+                        out.push_str(&format!("missing: {}\n", mismatch.path.display()))
+                    }
+                    MismatchKind::Unexpected => {
+                        out.push_str(&format!("unexpected: {}\n", mismatch.path.display()))
+                    }
+                    MismatchKind::Changed => out.push_str(&mismatch.diff),
+                }
+            }
+        }
+
+        let result = if self.failed == 0 { "ok" } else { "FAILED" };
+        out.push_str(&format!(
+            "\ntest result: {}. {} passed; {} failed",
+            result, self.passed, self.failed
+        ));
+        if self.blessed > 0 {
+            out.push_str(&format!("; {} blessed", self.blessed));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// A file of a generated project
+#[derive(Debug, Serialize)]
+pub struct GeneratedFile {
+    pub path: PathBuf,
+    pub size: usize,
+    /// Octal permissions, such as `0644`
+    pub mode: String,
+    pub sha256: String,
+}
+
+impl GeneratedFile {
+    pub fn new(file: &RenderedFile) -> Self {
+        Self {
+            path: file.path.clone(),
+            size: file.contents.len(),
+            mode: format!("{:04o}", file.mode),
+            sha256: to_hex(&Sha256::digest(&file.contents)),
+        }
+    }
+}
+
+/// What `essex new` generated, or would generate with `--dry-run`
+#[derive(Debug, Serialize)]
+pub struct NewReport {
+    pub template: String,
+    pub project: String,
+    pub directory: PathBuf,
+    pub dry_run: bool,
+    pub files: Vec<GeneratedFile>,
+    /// Template files left out by the manifest's rules
+    pub skipped: Vec<PathBuf>,
+    pub conflicts: Vec<Conflict>,
+    /// Every variable the templates were rendered with, defaults included
+    pub context: Value,
+}
+
+/// How the files `essex new --diff` would generate compare to those in a directory
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub template: String,
+    pub project: String,
+    pub directory: PathBuf,
+    pub files: Vec<FileDiff>,
+}
+
+/// What `essex update` did to each file
+#[derive(Debug, Serialize)]
+pub struct UpdateReport {
+    pub directory: PathBuf,
+    pub template: String,
+    pub files: Vec<UpdatedFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdatedFile {
+    pub path: PathBuf,
+    pub status: UpdateStatus,
+}
+
+/// The archive written by `essex template pack`
+#[derive(Debug, Serialize)]
+pub struct PackReport {
+    pub template: PathBuf,
+    pub archive: PathBuf,
+    pub sha256: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_file() {
+        let file = GeneratedFile::new(&RenderedFile {
+            path: PathBuf::from("bin/run.sh"),
+            contents: b"abc".to_vec(),
+            mode: 0o755,
+        });
+        assert_eq!(file.size, 3);
+        assert_eq!(file.mode, "0755");
+        assert_eq!(
+            file.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -9,6 +13,138 @@ pub enum Error {
     InvalidTemplate(String),
     InvalidPath(String),
     InvalidProjectName(String),
+    MissingVariable(String),
+    InvalidVariable(String),
+    InvalidValues(String),
+    SourceError(String),
+    HookError(String),
+    /// A failure in a template file, with its location when it is known
+    Diagnostic(Box<Diagnostic>),
+}
+
+/// A failure in a template file, pointing at the line and column when they are known
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// Template the file belongs to, none for files shared by every template
+    pub template: Option<String>,
+    /// Path of the file inside the template
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    /// The line of the file the error points at
+    #[serde(skip)]
+    pub snippet: Option<String>,
+}
+
+impl Diagnostic {
+    /// `file:line:column`, with as much of the position as is known
+    pub fn location(&self) -> String {
+        let mut location = self.file.display().to_string();
+        if let Some(line) = self.line {
+            location.push_str(&format!(":{}", line));
+            if let Some(column) = self.column {
+                location.push_str(&format!(":{}", column));
+            }
+        }
+        if let Some(template) = &self.template {
+            location.push_str(&format!(" in template '{}'", template));
+        }
+        location
+    }
+
+    /// The diagnostic as printed on a terminal: the message, where it happened, and the
+    /// offending line with a caret under the column
+    ///
+    /// ```text
+    /// error: Failed to parse 'Dockerfile': expected an identifier (must start with a-z)
+    ///   --> Dockerfile:2:17 in template 'basic'
+    ///   |
+    /// 2 | FROM {{ image | }}
+    ///   |                 ^
+    /// ```
+    pub fn render(&self) -> String {
+        self.render_as("error")
+    }
+
+    /// The diagnostic as printed on a terminal, headed by `level` instead of `error`, such as
+    /// `warning` or `error[shebang]`
+    pub fn render_as(&self, level: &str) -> String {
+        let mut out = format!("{}: {}\n", level, self.message);
+        let (Some(line), Some(snippet)) = (self.line, &self.snippet) else {
+            out.push_str(&format!("  --> {}\n", self.location()));
+            return out;
+        };
+
+        let gutter = " ".repeat(line.to_string().len());
+        out.push_str(&format!("{} --> {}\n", gutter, self.location()));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line, snippet));
+        if let Some(column) = self.column {
+            // Keep tabs so the caret lines up with the snippet
+            let indent: String = snippet
+                .chars()
+                .take(column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!("{} | {}^\n", gutter, indent));
+        }
+        out
+    }
+}
+
+impl Error {
+    /// Short, stable name of the kind of error, for tools reading `--json` output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::IoError(_) => "io",
+            Error::TemplateError(_) | Error::Diagnostic(_) => "template",
+            Error::TemplateNotFound(_) => "template_not_found",
+            Error::InvalidTemplate(_) => "invalid_template",
+            Error::InvalidPath(_) => "invalid_path",
+            Error::InvalidProjectName(_) => "invalid_project_name",
+            Error::MissingVariable(_) => "missing_variable",
+            Error::InvalidVariable(_) => "invalid_variable",
+            Error::InvalidValues(_) => "invalid_values",
+            Error::SourceError(_) => "source",
+            Error::HookError(_) => "hook",
+        }
+    }
+
+    /// An IO error that names the file it happened on, keeping the kind of the original
+    pub fn io_at(path: &Path, error: std::io::Error) -> Self {
+        Error::IoError(std::io::Error::new(
+            error.kind(),
+            format!("{}: {}", path.display(), error),
+        ))
+    }
+
+    /// Name the template a diagnostic belongs to, if it does not name one yet
+    pub fn in_template(self, template: &str) -> Self {
+        match self {
+            Error::Diagnostic(mut diagnostic) if diagnostic.template.is_none() => {
+                diagnostic.template = Some(template.to_string());
+                Error::Diagnostic(diagnostic)
+            }
+            error => error,
+        }
+    }
+
+    /// The error as a JSON object, with the template, file, line and column of diagnostics
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut error = json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+        });
+        if let Error::Diagnostic(diagnostic) = self {
+            error["message"] = json!(diagnostic.message);
+            error["template"] = json!(diagnostic.template);
+            error["file"] = json!(diagnostic.file);
+            error["line"] = json!(diagnostic.line);
+            error["column"] = json!(diagnostic.column);
+        }
+        json!({ "error": error })
+    }
 }
 
 impl std::error::Error for Error {}
@@ -22,6 +158,14 @@ impl std::fmt::Display for Error {
             Error::InvalidTemplate(e) => write!(f, "Invalid template: {}", e),
             Error::InvalidPath(e) => write!(f, "Invalid path: {}", e),
             Error::InvalidProjectName(e) => write!(f, "Invalid project name: {}", e),
+            Error::MissingVariable(e) => {
+                write!(f, "Missing variable: {} has no value and no default", e)
+            }
+            Error::InvalidVariable(e) => write!(f, "Invalid variable: {}", e),
+            Error::InvalidValues(e) => write!(f, "Invalid values: {}", e),
+            Error::SourceError(e) => write!(f, "Template source error: {}", e),
+            Error::HookError(e) => write!(f, "Hook failed: {}", e),
+            Error::Diagnostic(d) => write!(f, "Template error: {}: {}", d.location(), d.message),
         }
     }
 }
@@ -79,6 +223,105 @@ mod tests {
         assert!(error
             .to_string()
             .contains("Invalid project name: invalid name"));
+
+        // Test MissingVariable
+        let error = Error::MissingVariable("port".to_string());
+        assert!(error.to_string().contains("Missing variable: port"));
+
+        // Test InvalidVariable
+        let error = Error::InvalidVariable("port: 'abc' is not an integer".to_string());
+        assert!(error
+            .to_string()
+            .contains("Invalid variable: port: 'abc' is not an integer"));
+
+        // Test InvalidValues
+        let error = Error::InvalidValues("expected KEY=VALUE".to_string());
+        assert!(error
+            .to_string()
+            .contains("Invalid values: expected KEY=VALUE"));
+
+        // Test SourceError
+        let error = Error::SourceError("unknown ref 'v9'".to_string());
+        assert!(error
+            .to_string()
+            .contains("Template source error: unknown ref 'v9'"));
+
+        // Test HookError
+        let error = Error::HookError("post hook 'make fmt': exited with 2".to_string());
+        assert!(error
+            .to_string()
+            .contains("Hook failed: post hook 'make fmt': exited with 2"));
+
+        // Test Diagnostic
+        let error = Error::Diagnostic(Box::new(diagnostic()));
+        assert_eq!(
+            error.to_string(),
+            "Template error: Dockerfile:2:17 in template 'basic': expected an identifier"
+        );
+    }
+
+    fn diagnostic() -> Diagnostic {
+        Diagnostic {
+            template: Some("basic".to_string()),
+            file: PathBuf::from("Dockerfile"),
+            line: Some(2),
+            column: Some(17),
+            message: "expected an identifier".to_string(),
+            snippet: Some("FROM {{ image | }}".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_diagnostic_rendering() {
+        assert_eq!(
+            diagnostic().render(),
+            "error: expected an identifier\n  \
+             --> Dockerfile:2:17 in template 'basic'\n  \
+             |\n\
+             2 | FROM {{ image | }}\n  \
+             |                 ^\n"
+        );
+
+        let unlocated = Diagnostic {
+            template: None,
+            line: None,
+            column: None,
+            snippet: None,
+            ..diagnostic()
+        };
+        assert_eq!(
+            unlocated.render(),
+            "error: expected an identifier\n  --> Dockerfile\n"
+        );
+    }
+
+    #[test]
+    fn test_error_json() {
+        let error = Error::Diagnostic(Box::new(diagnostic()));
+        assert_eq!(
+            error.to_json(),
+            json!({
+                "error": {
+                    "kind": "template",
+                    "message": "expected an identifier",
+                    "template": "basic",
+                    "file": "Dockerfile",
+                    "line": 2,
+                    "column": 17,
+                }
+            })
+        );
+
+        let error = Error::TemplateNotFound("nope".to_string()).in_template("basic");
+        assert_eq!(
+            error.to_json(),
+            json!({
+                "error": {
+                    "kind": "template_not_found",
+                    "message": "Template not found: nope",
+                }
+            })
+        );
     }
 
     #[test]
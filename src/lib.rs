@@ -3,4 +3,6 @@ pub mod error;
 pub mod template;
 
 pub use error::{Error, Result};
-pub use template::{TemplateContext, TemplateEngine};
+pub use template::{
+    FsOutput, MemoryOutput, Output, TarOutput, TemplateContext, TemplateEngine, ZipOutput,
+};
@@ -1,14 +1,18 @@
 use clap::Parser;
-use cli::Cli;
-use error::Result;
+use essex::cli::report::Format;
+use essex::cli::{report_error, Cli};
+use std::process::ExitCode;
 
-mod cli;
-mod error;
-mod template;
-
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    cli.execute()
+    let json = cli.json || cli.format == Format::Json;
+    match cli.execute() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            report_error(&error, json);
+            ExitCode::FAILURE
+        }
+    }
 }
 
 #[cfg(test)]
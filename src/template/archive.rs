@@ -0,0 +1,414 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::manifest::MANIFEST_FILE;
+use super::source::{is_vcs_dir, Template, TemplateFile, TemplateSource};
+use crate::error::{Error, Result};
+
+/// Archive formats templates can be distributed as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the format from the file name: `.tar.gz`, `.tgz` or `.zip`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Name of the template packed in an archive: the file name without its extension
+fn archive_stem(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    [".tar.gz", ".tgz", ".zip"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name)
+        .to_string()
+}
+
+/// Load a template from a `.tar.gz` or `.zip` archive.
+///
+/// The archive is read into memory. A single top-level directory wrapping every file is
+/// treated as the template root. If a `<archive>.sha256` file sits next to the archive, the
+/// archive must match it.
+pub fn load(path: &Path) -> Result<Template> {
+    let format = ArchiveFormat::from_path(path).ok_or_else(|| {
+        Error::SourceError(format!("{}: not a .tar.gz or .zip archive", path.display()))
+    })?;
+    verify_checksum(path)?;
+
+    let mut files = match format {
+        ArchiveFormat::TarGz => read_tar_gz(path)?,
+        ArchiveFormat::Zip => read_zip(path)?,
+    };
+    strip_common_root(&mut files);
+
+    if files.is_empty() {
+        return Err(Error::SourceError(format!(
+            "{}: archive contains no template files",
+            path.display()
+        )));
+    }
+    if !files.iter().any(|f| f.path == Path::new(MANIFEST_FILE)) {
+        if let Some(nested) = files.iter().find(|f| f.path.ends_with(MANIFEST_FILE)) {
+            return Err(Error::SourceError(format!(
+                "{}: template root must be at the top of the archive, found {}",
+                path.display(),
+                nested.path.display()
+            )));
+        }
+    }
+
+    Template::new(
+        &archive_stem(path),
+        TemplateSource::Archive(path.to_path_buf()),
+        files,
+    )
+}
+
+fn read_tar_gz(path: &Path) -> Result<Vec<TemplateFile>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let entry_path = checked_path(path, &entry.path()?)?;
+        let mode = entry.header().mode().ok().map(|mode| mode & 0o777);
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.push(TemplateFile {
+            path: entry_path,
+            contents,
+            mode,
+        });
+    }
+    Ok(files)
+}
+
+fn read_zip(path: &Path) -> Result<Vec<TemplateFile>> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(|e| zip_error(path, e))?;
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| zip_error(path, e))?;
+        if !entry.is_file() {
+            continue;
+        }
+
+        let entry_path = checked_path(path, Path::new(entry.name()))?;
+        let mode = entry.unix_mode().map(|mode| mode & 0o777);
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.push(TemplateFile {
+            path: entry_path,
+            contents,
+            mode,
+        });
+    }
+    Ok(files)
+}
+
+/// Reject entries that would land outside the template root
+fn checked_path(archive: &Path, entry: &Path) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in entry.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(Error::SourceError(format!(
+                    "{}: entry '{}' escapes the template root",
+                    archive.display(),
+                    entry.display()
+                )))
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Drop a top-level directory shared by every file, such as `python-service-1.4/`
+fn strip_common_root(files: &mut [TemplateFile]) {
+    let root = match files.first().and_then(|f| f.path.components().next()) {
+        Some(Component::Normal(root)) => PathBuf::from(root),
+        _ => return,
+    };
+    if files
+        .iter()
+        .all(|f| f.path.starts_with(&root) && f.path != root)
+    {
+        for file in files.iter_mut() {
+            file.path = file.path.strip_prefix(&root).unwrap().to_path_buf();
+        }
+    }
+}
+
+fn zip_error(path: &Path, error: zip::result::ZipError) -> Error {
+    Error::SourceError(format!("{}: {}", path.display(), error))
+}
+
+/// Path of the checksum file written next to an archive
+pub fn checksum_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// SHA-256 of a file as lowercase hex
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn verify_checksum(archive: &Path) -> Result<()> {
+    let checksum_file = checksum_path(archive);
+    if !checksum_file.is_file() {
+        return Ok(());
+    }
+
+    let content = std::fs::read_to_string(&checksum_file)?;
+    let expected = content.split_whitespace().next().unwrap_or_default();
+    let actual = sha256_file(archive)?;
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(Error::SourceError(format!(
+            "{}: checksum mismatch (expected {}, got {})",
+            archive.display(),
+            expected,
+            actual
+        )));
+    }
+    Ok(())
+}
+
+/// Pack the template in `dir` into an archive at `output`, writing a `sha256sum` style
+/// checksum file next to it. Returns the archive's SHA-256.
+///
+/// The template is loaded first so that broken manifests are caught before anything is
+/// distributed. Files are stored under a top-level directory named after the archive.
+pub fn pack(dir: &Path, output: &Path) -> Result<String> {
+    let format = ArchiveFormat::from_path(output).ok_or_else(|| {
+        Error::InvalidPath(format!(
+            "{}: archive name must end in .tar.gz, .tgz or .zip",
+            output.display()
+        ))
+    })?;
+
+    let template = Template::from_dir(&archive_stem(output), dir)?;
+    if template.files.is_empty() {
+        return Err(Error::InvalidTemplate(format!(
+            "{}: template has no files",
+            dir.display()
+        )));
+    }
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && is_vcs_dir(e.file_name())))
+    {
+        let entry = entry.map_err(|e| Error::InvalidPath(e.to_string()))?;
+        if entry.file_type().is_file() {
+            let rel_path = entry.path().strip_prefix(dir)?.to_path_buf();
+            entries.push((entry.path().to_path_buf(), rel_path));
+        }
+    }
+
+    let root = PathBuf::from(&template.name);
+    let file = File::create(output)?;
+    match format {
+        ArchiveFormat::TarGz => {
+            let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+            for (path, rel_path) in &entries {
+                builder.append_path_with_name(path, root.join(rel_path))?;
+            }
+            builder.into_inner()?.finish()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut writer = ZipWriter::new(file);
+            for (path, rel_path) in &entries {
+                let name = root
+                    .join(rel_path)
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .unix_permissions(file_mode(path)?);
+                writer
+                    .start_file(name, options)
+                    .map_err(|e| zip_error(output, e))?;
+                writer.write_all(&std::fs::read(path)?)?;
+            }
+            writer.finish().map_err(|e| zip_error(output, e))?;
+        }
+    }
+
+    let checksum = sha256_file(output)?;
+    let file_name = output
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    std::fs::write(
+        checksum_path(output),
+        format!("{}  {}\n", checksum, file_name),
+    )?;
+
+    Ok(checksum)
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Result<u32> {
+    Ok(0o644)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn template_dir(root: &Path) -> PathBuf {
+        let dir = root.join("python-service");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("essex.toml"), "version = \"1.4.0\"\n").unwrap();
+        std::fs::write(dir.join("README.md"), "# {{ image_name }}\n").unwrap();
+        std::fs::write(dir.join("src/main.py"), "print('hi')\n").unwrap();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_archive_format_from_path() {
+        let format = |p: &str| ArchiveFormat::from_path(Path::new(p));
+        assert_eq!(format("t-1.4.tar.gz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("t.tgz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("dist/t.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("basic"), None);
+        assert_eq!(archive_stem(Path::new("dist/t-1.4.tar.gz")), "t-1.4");
+    }
+
+    #[test]
+    fn test_pack_and_load_round_trip() -> Result<()> {
+        let root = tempdir()?;
+        let dir = template_dir(root.path());
+
+        for name in ["python-service-1.4.tar.gz", "python-service-1.4.zip"] {
+            let archive = root.path().join(name);
+            let checksum = pack(&dir, &archive)?;
+            assert_eq!(checksum, sha256_file(&archive)?);
+            assert_eq!(
+                std::fs::read_to_string(checksum_path(&archive))?,
+                format!("{}  {}\n", checksum, name)
+            );
+
+            let template = load(&archive)?;
+            assert_eq!(template.name, "python-service-1.4");
+            assert_eq!(template.source, TemplateSource::Archive(archive.clone()));
+            assert_eq!(template.manifest.version.as_deref(), Some("1.4.0"));
+            let paths: Vec<_> = template.files.iter().map(|f| f.path.clone()).collect();
+            assert_eq!(
+                paths,
+                vec![PathBuf::from("README.md"), PathBuf::from("src/main.py")]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_tampered_archive() -> Result<()> {
+        let root = tempdir()?;
+        let dir = template_dir(root.path());
+        let archive = root.path().join("t.tar.gz");
+        pack(&dir, &archive)?;
+
+        std::fs::write(
+            checksum_path(&archive),
+            format!("{}  t.tar.gz\n", "0".repeat(64)),
+        )?;
+        let err = load(&archive).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_template_root() -> Result<()> {
+        let root = tempdir()?;
+
+        // The template is nested one level too deep
+        let nested = root.path().join("nested.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&nested)?,
+            Compression::default(),
+        ));
+        for (path, contents) in [
+            ("dist/python/essex.toml", "version = \"1.0.0\"\n"),
+            ("dist/python/README.md", "# readme\n"),
+            ("dist/CHANGELOG.md", "changes\n"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+        let err = load(&nested).unwrap_err();
+        assert!(err.to_string().contains("python/essex.toml"), "{}", err);
+
+        // Entries may not escape the template root
+        let escaping = root.path().join("escaping.zip");
+        let mut writer = ZipWriter::new(File::create(&escaping)?);
+        writer
+            .start_file("../evil.sh", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"rm -rf /")?;
+        writer.finish().unwrap();
+        let err = load(&escaping).unwrap_err();
+        assert!(
+            err.to_string().contains("escapes the template root"),
+            "{}",
+            err
+        );
+
+        let empty = root.path().join("empty.zip");
+        ZipWriter::new(File::create(&empty)?).finish().unwrap();
+        assert!(matches!(load(&empty), Err(Error::SourceError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as Yaml;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+/// Separator between the layers of a composed template, as in `basic+compose+github-actions`
+pub const LAYER_SEPARATOR: char = '+';
+
+/// How a layer combines a file with the one an earlier layer already produced
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// Keep the later layer's file
+    #[default]
+    Replace,
+    /// Add the later layer's file after the earlier one, such as extra Makefile targets
+    Append,
+    /// Deep-merge both files as YAML, with the later layer winning for anything but mappings
+    Yaml,
+}
+
+impl fmt::Display for MergeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeStrategy::Replace => write!(f, "replaced"),
+            MergeStrategy::Append => write!(f, "appended"),
+            MergeStrategy::Yaml => write!(f, "merged as YAML"),
+        }
+    }
+}
+
+/// How files matching a glob are merged when an earlier layer produced them too
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeRule {
+    pub glob: String,
+    pub strategy: MergeStrategy,
+}
+
+/// A file produced by more than one layer of a composed template
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+    /// Layers that produced the file, in order
+    pub layers: Vec<String>,
+    /// How the last layer combined its file with the earlier ones
+    pub strategy: MergeStrategy,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.path.display(),
+            self.layers.join(", "),
+            self.strategy
+        )?;
+        if self.strategy == MergeStrategy::Replace {
+            if let Some(last) = self.layers.last() {
+                write!(f, " by {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a composed template reference and the `--with` templates into layers, in order.
+///
+/// `git+` references keep their `+`, so `basic+git+https://host/repo.git#ci` has two layers.
+pub fn layer_names(template: &str, with: &[String]) -> Vec<String> {
+    let mut layers: Vec<String> = Vec::new();
+    for part in template.split(LAYER_SEPARATOR) {
+        match layers.last_mut() {
+            Some(last) if last == "git" => {
+                last.push(LAYER_SEPARATOR);
+                last.push_str(part);
+            }
+            _ => layers.push(part.to_string()),
+        }
+    }
+    layers.extend(with.iter().cloned());
+    layers.retain(|layer| !layer.is_empty());
+    layers
+}
+
+/// Combine the contents an earlier layer produced for `path` with a later layer's
+pub fn merge(strategy: MergeStrategy, path: &Path, base: &[u8], overlay: &[u8]) -> Result<Vec<u8>> {
+    match strategy {
+        MergeStrategy::Replace => Ok(overlay.to_vec()),
+        MergeStrategy::Append => {
+            let mut merged = base.to_vec();
+            if !merged.is_empty() && !merged.ends_with(b"\n") {
+                merged.push(b'\n');
+            }
+            if !merged.is_empty() && !overlay.is_empty() {
+                merged.push(b'\n');
+            }
+            merged.extend_from_slice(overlay);
+            Ok(merged)
+        }
+        MergeStrategy::Yaml => {
+            let parse = |contents: &[u8]| {
+                serde_yaml::from_slice::<Yaml>(contents).map_err(|e| {
+                    Error::TemplateError(format!("{}: cannot merge as YAML: {}", path.display(), e))
+                })
+            };
+            let mut merged = parse(base)?;
+            deep_merge(&mut merged, parse(overlay)?);
+            serde_yaml::to_string(&merged)
+                .map(String::into_bytes)
+                .map_err(|e| Error::TemplateError(format!("{}: {}", path.display(), e)))
+        }
+    }
+}
+
+/// Merge `overlay` into `base`: mappings are merged key by key, anything else is replaced
+fn deep_merge(base: &mut Yaml, overlay: Yaml) {
+    match (base, overlay) {
+        (Yaml::Mapping(base), Yaml::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, Yaml::Null) => {}
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_names() {
+        assert_eq!(
+            layer_names("basic+compose+github-actions", &[]),
+            vec!["basic", "compose", "github-actions"]
+        );
+        assert_eq!(
+            layer_names("basic", &["compose".to_string()]),
+            vec!["basic", "compose"]
+        );
+        assert_eq!(
+            layer_names("basic+git+file:///srv/t.git#ci@v1", &[]),
+            vec!["basic", "git+file:///srv/t.git#ci@v1"]
+        );
+        assert_eq!(
+            layer_names("git+file:///srv/t.git#ci", &[]),
+            vec!["git+file:///srv/t.git#ci"]
+        );
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let path = Path::new("Makefile");
+        assert_eq!(
+            merge(MergeStrategy::Replace, path, b"a\n", b"b\n")?,
+            b"b\n".to_vec()
+        );
+        assert_eq!(
+            merge(
+                MergeStrategy::Append,
+                path,
+                b"build:\n\tdocker build .",
+                b"up:\n\tdocker compose up\n"
+            )?,
+            b"build:\n\tdocker build .\n\nup:\n\tdocker compose up\n".to_vec()
+        );
+
+        let path = Path::new("config.yml");
+        let merged = merge(
+            MergeStrategy::Yaml,
+            path,
+            b"name: app\nservices:\n  web:\n    image: app\n    ports: [80]\n",
+            b"services:\n  web:\n    ports: [8080]\n  db:\n    image: postgres\n",
+        )?;
+        let merged: Yaml = serde_yaml::from_slice(&merged).unwrap();
+        let expected: Yaml = serde_yaml::from_str(
+            "name: app\nservices:\n  web:\n    image: app\n    ports: [8080]\n  db:\n    image: postgres\n",
+        )
+        .unwrap();
+        assert_eq!(merged, expected);
+
+        assert!(matches!(
+            merge(MergeStrategy::Yaml, path, b"a: [", b"a: 1"),
+            Err(Error::TemplateError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_display() {
+        let conflict = Conflict {
+            path: PathBuf::from("README.md"),
+            layers: vec!["basic".to_string(), "compose".to_string()],
+            strategy: MergeStrategy::Replace,
+        };
+        assert_eq!(
+            conflict.to_string(),
+            "README.md (basic, compose): replaced by compose"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Local, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use tera::{Error, Result, Tera};
+
+/// Longest tag Docker accepts
+const MAX_TAG_LEN: usize = 128;
+
+/// Longest DNS-1123 label, as used for Kubernetes names
+const MAX_LABEL_LEN: usize = 63;
+
+/// Register the filters and functions templates can use on top of Tera's built-ins:
+///
+/// - `docker_tag`, `dns_label`, `screaming_snake`, `kebab` and `pascal` turn strings into
+///   image tags, Kubernetes names, environment variable names and so on
+/// - `semver_bump(part="minor")` bumps a version, the patch by default
+/// - `uuid()` returns a random UUID
+/// - `now(fmt="%Y-%m-%d", utc=true)` formats the current time, as RFC 3339 without `fmt`
+/// - `env(name="HOME", default="~")` reads an environment variable
+pub fn register(tera: &mut Tera) {
+    tera.register_filter("docker_tag", docker_tag);
+    tera.register_filter("dns_label", dns_label);
+    tera.register_filter("screaming_snake", screaming_snake);
+    tera.register_filter("kebab", kebab);
+    tera.register_filter("pascal", pascal);
+    tera.register_filter("semver_bump", semver_bump);
+    tera.register_function("uuid", uuid);
+    tera.register_function("now", now);
+    tera.register_function("env", env);
+}
+
+/// A valid image tag: invalid characters become `-`, it cannot start with `.` or `-`, and
+/// it is cut to 128 characters
+fn docker_tag(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "docker_tag")?;
+    let tag: String = input
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let tag: String = tag
+        .trim_start_matches(['.', '-'])
+        .chars()
+        .take(MAX_TAG_LEN)
+        .collect();
+
+    if tag.is_empty() {
+        return Err(Error::msg(format!(
+            "docker_tag: '{}' has no characters valid in a tag",
+            input
+        )));
+    }
+    Ok(Value::String(tag))
+}
+
+/// A DNS-1123 label: lowercase letters, digits and single `-`, starting and ending with a
+/// letter or digit, at most 63 characters
+fn dns_label(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "dns_label")?;
+    let label = words(input)
+        .iter()
+        .map(|word| {
+            word.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_ascii_lowercase();
+    let label = label[..label.len().min(MAX_LABEL_LEN)].trim_end_matches('-');
+
+    if label.is_empty() {
+        return Err(Error::msg(format!(
+            "dns_label: '{}' has no characters valid in a DNS label",
+            input
+        )));
+    }
+    Ok(Value::String(label.to_string()))
+}
+
+/// `my-app` becomes `MY_APP`
+fn screaming_snake(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "screaming_snake")?;
+    Ok(Value::String(words(input).join("_").to_uppercase()))
+}
+
+/// `MyApp` becomes `my-app`
+fn kebab(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "kebab")?;
+    Ok(Value::String(words(input).join("-").to_lowercase()))
+}
+
+/// `my-app` becomes `MyApp`
+fn pascal(value: &Value, _: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "pascal")?;
+    let pascal = words(input)
+        .iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    Ok(Value::String(pascal))
+}
+
+/// Bump the `major`, `minor` or `patch` (default) part of a version such as `1.2.3` or
+/// `v1.2.3-rc.1`, dropping any pre-release or build suffix
+fn semver_bump(value: &Value, args: &HashMap<String, Value>) -> Result<Value> {
+    let input = string(value, "semver_bump")?;
+    let part = match args.get("part") {
+        Some(part) => part
+            .as_str()
+            .ok_or_else(|| Error::msg("semver_bump: `part` must be a string"))?,
+        None => "patch",
+    };
+
+    let (prefix, version) = match input.strip_prefix('v') {
+        Some(rest) => ("v", rest),
+        None => ("", input),
+    };
+    let core = version.split(['-', '+']).next().unwrap_or_default();
+    let numbers: Vec<u64> = core
+        .split('.')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()
+        .filter(|numbers: &Vec<u64>| numbers.len() == 3)
+        .ok_or_else(|| Error::msg(format!("semver_bump: '{}' is not a version", input)))?;
+    let (major, minor, patch) = (numbers[0], numbers[1], numbers[2]);
+
+    let bumped = match part {
+        "major" => (major + 1, 0, 0),
+        "minor" => (major, minor + 1, 0),
+        "patch" => (major, minor, patch + 1),
+        other => {
+            return Err(Error::msg(format!(
+                "semver_bump: unknown part '{}', expected major, minor or patch",
+                other
+            )))
+        }
+    };
+    Ok(Value::String(format!(
+        "{}{}.{}.{}",
+        prefix, bumped.0, bumped.1, bumped.2
+    )))
+}
+
+fn uuid(_: &HashMap<String, Value>) -> Result<Value> {
+    Ok(Value::String(uuid::Uuid::new_v4().to_string()))
+}
+
+fn now(args: &HashMap<String, Value>) -> Result<Value> {
+    let utc = match args.get("utc") {
+        Some(utc) => utc
+            .as_bool()
+            .ok_or_else(|| Error::msg("now: `utc` must be a boolean"))?,
+        None => false,
+    };
+    let fmt = match args.get("fmt") {
+        Some(fmt) => Some(
+            fmt.as_str()
+                .ok_or_else(|| Error::msg("now: `fmt` must be a string"))?,
+        ),
+        None => None,
+    };
+    // chrono panics when displaying an invalid format, so check it first
+    if let Some(fmt) = fmt {
+        if StrftimeItems::new(fmt).any(|item| matches!(item, Item::Error)) {
+            return Err(Error::msg(format!("now: invalid format '{}'", fmt)));
+        }
+    }
+
+    let formatted = match (fmt, utc) {
+        (Some(fmt), true) => Utc::now().format(fmt).to_string(),
+        (Some(fmt), false) => Local::now().format(fmt).to_string(),
+        (None, true) => Utc::now().to_rfc3339(),
+        (None, false) => Local::now().to_rfc3339(),
+    };
+    Ok(Value::String(formatted))
+}
+
+fn env(args: &HashMap<String, Value>) -> Result<Value> {
+    let name = args
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::msg("env: `name` must be a string"))?;
+
+    match (std::env::var(name), args.get("default")) {
+        (Ok(value), _) => Ok(Value::String(value)),
+        (Err(_), Some(default)) => Ok(default.clone()),
+        (Err(_), None) => Err(Error::msg(format!(
+            "env: '{}' is not set and no default was given",
+            name
+        ))),
+    }
+}
+
+fn string<'a>(value: &'a Value, filter: &str) -> Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| Error::msg(format!("{}: expected a string, got {}", filter, value)))
+}
+
+/// Split a name into words at separators and at case changes, so `myApp`, `my_app`,
+/// `MY-APP` and `HTTPServer` all give their parts
+fn words(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let chars: Vec<char> = input.chars().collect();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+
+        if c.is_uppercase() && !current.is_empty() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                words.push(std::mem::take(&mut current));
+            }
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tera::Context;
+
+    fn render(template: &str) -> Result<String> {
+        let mut tera = Tera::default();
+        register(&mut tera);
+        tera.render_str(template, &Context::new())
+    }
+
+    #[test]
+    fn test_naming_filters() {
+        let cases = [
+            (
+                "{{ 'My App/feature#12' | docker_tag }}",
+                "My-App-feature-12",
+            ),
+            ("{{ '.-v1.2' | docker_tag }}", "v1.2"),
+            ("{{ 'My_App.Service' | dns_label }}", "my-app-service"),
+            ("{{ '--api--' | dns_label }}", "api"),
+            ("{{ 'my-app' | screaming_snake }}", "MY_APP"),
+            ("{{ 'HTTPServer2go' | screaming_snake }}", "HTTP_SERVER2GO"),
+            ("{{ 'myApp v2' | kebab }}", "my-app-v2"),
+            ("{{ 'my_app-server' | pascal }}", "MyAppServer"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(render(template).unwrap(), expected, "{}", template);
+        }
+
+        let long = format!("{{{{ '{}' | dns_label }}}}", "a".repeat(70));
+        assert_eq!(render(&long).unwrap().len(), MAX_LABEL_LEN);
+        let long = format!("{{{{ '{}' | docker_tag }}}}", "a".repeat(200));
+        assert_eq!(render(&long).unwrap().len(), MAX_TAG_LEN);
+
+        assert!(render("{{ '...' | docker_tag }}").is_err());
+        assert!(render("{{ '__' | dns_label }}").is_err());
+        assert!(render("{{ 3 | kebab }}").is_err());
+    }
+
+    #[test]
+    fn test_semver_bump() {
+        assert_eq!(render("{{ '1.2.3' | semver_bump }}").unwrap(), "1.2.4");
+        assert_eq!(
+            render("{{ 'v1.2.3-rc.1' | semver_bump(part='minor') }}").unwrap(),
+            "v1.3.0"
+        );
+        assert_eq!(
+            render("{{ '1.2.3' | semver_bump(part='major') }}").unwrap(),
+            "2.0.0"
+        );
+        assert!(render("{{ '1.2' | semver_bump }}").is_err());
+        assert!(render("{{ '1.2.3' | semver_bump(part='build') }}").is_err());
+    }
+
+    #[test]
+    fn test_functions() {
+        let id = render("{{ uuid() }}").unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok(), "{}", id);
+        assert_ne!(id, render("{{ uuid() }}").unwrap());
+
+        let year = render("{{ now(fmt='%Y', utc=true) }}").unwrap();
+        assert_eq!(year, Utc::now().format("%Y").to_string());
+        assert!(render("{{ now() }}").unwrap().contains('T'));
+        assert!(render("{{ now(fmt='%Q') }}").is_err());
+
+        std::env::set_var("ESSEX_FILTERS_TEST", "from-env");
+        assert_eq!(
+            render("{{ env(name='ESSEX_FILTERS_TEST') }}").unwrap(),
+            "from-env"
+        );
+        assert_eq!(
+            render("{{ env(name='ESSEX_FILTERS_UNSET', default='fallback') }}").unwrap(),
+            "fallback"
+        );
+        assert!(render("{{ env(name='ESSEX_FILTERS_UNSET') }}").is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use crate::error::{Error, Result};
+
+/// A template stored in a git repository.
+///
+/// Two forms are accepted:
+///
+/// - `git+<url>[#<subdir>][@<ref>]`, for example `git+file:///srv/templates.git#python@v1.2`
+/// - `gh:<org>/<repo>[//<subdir>][@<ref>]`, for example `gh:acme/templates//python@v1.2`
+///
+/// The ref can be a tag, a branch or a commit; without one the repository's `HEAD` is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitReference {
+    pub url: String,
+    pub subdir: Option<PathBuf>,
+    pub reference: Option<String>,
+}
+
+/// A git template checked out at a resolved commit
+#[derive(Debug, Clone)]
+pub struct GitCheckout {
+    /// Directory holding the template files
+    pub path: PathBuf,
+    pub commit: String,
+}
+
+impl GitReference {
+    /// Parse a template reference, returning `None` if it does not point to a git repository
+    pub fn parse(reference: &str) -> Option<Self> {
+        if let Some(rest) = reference.strip_prefix("git+") {
+            let (url, subdir, reference) = match rest.split_once('#') {
+                Some((url, fragment)) => {
+                    let (subdir, reference) = split_ref(fragment, 0);
+                    (url, Some(subdir), reference)
+                }
+                None => {
+                    let (url, reference) = split_ref(rest, rest.rfind('/').unwrap_or(0));
+                    (url, None, reference)
+                }
+            };
+            return Some(Self::new(url.to_string(), subdir, reference));
+        }
+
+        if let Some(rest) = reference.strip_prefix("gh:") {
+            let (repo, subdir, reference) = match rest.split_once("//") {
+                Some((repo, path)) => {
+                    let (subdir, reference) = split_ref(path, 0);
+                    (repo, Some(subdir), reference)
+                }
+                None => {
+                    let (repo, reference) = split_ref(rest, 0);
+                    (repo, None, reference)
+                }
+            };
+            let url = format!("https://github.com/{}.git", repo.trim_end_matches(".git"));
+            return Some(Self::new(url, subdir, reference));
+        }
+
+        None
+    }
+
+    fn new(url: String, subdir: Option<&str>, reference: Option<&str>) -> Self {
+        Self {
+            url,
+            subdir: subdir
+                .map(|s| s.trim_matches('/'))
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from),
+            reference: reference.filter(|r| !r.is_empty()).map(str::to_string),
+        }
+    }
+
+    /// Fetch the repository into `cache_dir` and check out the requested ref.
+    ///
+    /// Each repository is mirrored once and each commit checked out once, so pinned
+    /// references work offline after the first fetch.
+    pub fn fetch(&self, cache_dir: &Path) -> Result<GitCheckout> {
+        // Whatever git is given must not be taken for one of its options
+        if self.url.starts_with('-') {
+            return Err(Error::SourceError(format!(
+                "invalid git URL '{}'",
+                self.url
+            )));
+        }
+        if let Some(reference) = self.reference.as_deref().filter(|r| r.starts_with('-')) {
+            return Err(Error::SourceError(format!(
+                "{}: invalid ref '{}'",
+                self.url, reference
+            )));
+        }
+        if let Some(subdir) = &self.subdir {
+            if !subdir
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(Error::SourceError(format!(
+                    "invalid subdirectory '{}' in {}",
+                    subdir.display(),
+                    self.url
+                )));
+            }
+        }
+
+        let repo_dir = cache_dir.join("git").join(cache_key(&self.url));
+        let mirror = repo_dir.join("mirror.git");
+        let revision = format!("{}^{{commit}}", self.reference.as_deref().unwrap_or("HEAD"));
+
+        if !mirror.exists() {
+            std::fs::create_dir_all(&repo_dir)?;
+            git(
+                &[
+                    "clone",
+                    "--quiet",
+                    "--mirror",
+                    "--",
+                    &self.url,
+                    path_str(&mirror)?,
+                ],
+                None,
+            )?;
+        } else if let Err(e) = git(&["fetch", "--quiet", "--prune", "origin"], Some(&mirror)) {
+            // Keep going offline as long as the ref is already known
+            if git(
+                &["rev-parse", "--verify", "--quiet", &revision],
+                Some(&mirror),
+            )
+            .is_err()
+            {
+                return Err(e);
+            }
+        }
+
+        let commit = git(&["rev-parse", "--verify", &revision], Some(&mirror))
+            .map_err(|_| {
+                Error::SourceError(format!(
+                    "{}: unknown ref '{}'",
+                    self.url,
+                    self.reference.as_deref().unwrap_or("HEAD")
+                ))
+            })?
+            .trim()
+            .to_string();
+
+        let checkout = repo_dir.join(&commit);
+        if !checkout.exists() {
+            let staging = tempfile::Builder::new()
+                .prefix(".checkout-")
+                .tempdir_in(&repo_dir)?;
+            let work_tree = staging.path().join("tree");
+            git(
+                &[
+                    "clone",
+                    "--quiet",
+                    "--no-checkout",
+                    "--",
+                    path_str(&mirror)?,
+                    path_str(&work_tree)?,
+                ],
+                None,
+            )?;
+            git(&["checkout", "--quiet", &commit], Some(&work_tree))?;
+            std::fs::remove_dir_all(work_tree.join(".git"))?;
+            std::fs::rename(&work_tree, &checkout)?;
+        }
+
+        let path = match &self.subdir {
+            Some(subdir) => checkout.join(subdir),
+            None => checkout,
+        };
+        if !path.is_dir() {
+            return Err(Error::SourceError(format!(
+                "{}: no directory '{}' at {}",
+                self.url,
+                self.subdir.as_deref().unwrap_or(Path::new("")).display(),
+                commit
+            )));
+        }
+
+        Ok(GitCheckout { path, commit })
+    }
+}
+
+/// Split `value@ref`, only looking for the `@` after byte offset `from`
+fn split_ref(value: &str, from: usize) -> (&str, Option<&str>) {
+    match value[from..].rfind('@') {
+        Some(index) => (&value[..from + index], Some(&value[from + index + 1..])),
+        None => (value, None),
+    }
+}
+
+/// Turn a repository URL into a directory name for the cache
+fn cache_key(url: &str) -> String {
+    url.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| Error::InvalidPath(path.display().to_string()))
+}
+
+fn git(args: &[&str], dir: Option<&Path>) -> Result<String> {
+    let mut command = Command::new("git");
+    command.args(args).env("GIT_TERMINAL_PROMPT", "0");
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    let output = command
+        .output()
+        .map_err(|e| Error::SourceError(format!("failed to run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::SourceError(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.email=test@example.com",
+                "-c",
+                "user.name=Test User",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Create a bare repository with a template in `python/`, tagged `v1` before a second
+    /// commit changes it
+    fn bare_repo(root: &Path) -> (PathBuf, String) {
+        let work = root.join("work");
+        std::fs::create_dir_all(work.join("python")).unwrap();
+        run(&work, &["init", "--quiet"]);
+        std::fs::write(work.join("python/README.md"), "# {{ image_name }} v1\n").unwrap();
+        run(&work, &["add", "."]);
+        run(&work, &["commit", "--quiet", "-m", "v1"]);
+        run(&work, &["tag", "v1"]);
+        let v1 = run(&work, &["rev-parse", "HEAD"]);
+
+        std::fs::write(work.join("python/README.md"), "# {{ image_name }} v2\n").unwrap();
+        run(&work, &["commit", "--quiet", "-am", "v2"]);
+
+        let bare = root.join("templates.git");
+        run(
+            root,
+            &["clone", "--quiet", "--bare", "work", "templates.git"],
+        );
+        (bare, v1)
+    }
+
+    #[test]
+    fn test_parse_git_reference() {
+        assert_eq!(
+            GitReference::parse("git+file:///srv/templates.git#python@v1.2"),
+            Some(GitReference {
+                url: "file:///srv/templates.git".to_string(),
+                subdir: Some(PathBuf::from("python")),
+                reference: Some("v1.2".to_string()),
+            })
+        );
+        assert_eq!(
+            GitReference::parse("git+ssh://git@example.com/org/repo.git@main"),
+            Some(GitReference {
+                url: "ssh://git@example.com/org/repo.git".to_string(),
+                subdir: None,
+                reference: Some("main".to_string()),
+            })
+        );
+        assert_eq!(
+            GitReference::parse("git+ssh://git@example.com/org/repo.git"),
+            Some(GitReference {
+                url: "ssh://git@example.com/org/repo.git".to_string(),
+                subdir: None,
+                reference: None,
+            })
+        );
+        assert_eq!(
+            GitReference::parse("gh:acme/templates//docker/python@v2"),
+            Some(GitReference {
+                url: "https://github.com/acme/templates.git".to_string(),
+                subdir: Some(PathBuf::from("docker/python")),
+                reference: Some("v2".to_string()),
+            })
+        );
+        assert_eq!(
+            GitReference::parse("gh:acme/templates"),
+            Some(GitReference {
+                url: "https://github.com/acme/templates.git".to_string(),
+                subdir: None,
+                reference: None,
+            })
+        );
+        assert_eq!(GitReference::parse("basic"), None);
+    }
+
+    #[test]
+    fn test_fetch_from_bare_repo() -> Result<()> {
+        let root = tempdir()?;
+        let cache = tempdir()?;
+        let (bare, v1) = bare_repo(root.path());
+        let url = format!("file://{}", bare.display());
+
+        let pinned = GitReference::parse(&format!("git+{}#python@v1", url)).unwrap();
+        let checkout = pinned.fetch(cache.path())?;
+        assert_eq!(checkout.commit, v1);
+        assert_eq!(
+            std::fs::read_to_string(checkout.path.join("README.md"))?,
+            "# {{ image_name }} v1\n"
+        );
+        assert!(!checkout.path.join(".git").exists());
+
+        // Pinning to the commit itself resolves to the same checkout
+        let by_commit = GitReference::parse(&format!("git+{}#python@{}", url, v1)).unwrap();
+        assert_eq!(by_commit.fetch(cache.path())?.path, checkout.path);
+
+        let latest = GitReference::parse(&format!("git+{}#python", url)).unwrap();
+        let checkout = latest.fetch(cache.path())?;
+        assert_ne!(checkout.commit, v1);
+        assert_eq!(
+            std::fs::read_to_string(checkout.path.join("README.md"))?,
+            "# {{ image_name }} v2\n"
+        );
+
+        let unknown = GitReference::parse(&format!("git+{}#python@v9", url)).unwrap();
+        assert!(matches!(
+            unknown.fetch(cache.path()),
+            Err(Error::SourceError(_))
+        ));
+
+        let missing = GitReference::parse(&format!("git+{}#node@v1", url)).unwrap();
+        assert!(matches!(
+            missing.fetch(cache.path()),
+            Err(Error::SourceError(_))
+        ));
+
+        let escape = GitReference::parse(&format!("git+{}#../x@v1", url)).unwrap();
+        assert!(matches!(
+            escape.fetch(cache.path()),
+            Err(Error::SourceError(_))
+        ));
+
+        // Options smuggled in as the URL or the ref are refused before git runs
+        let marker = root.path().join("ran");
+        let option = GitReference::parse(&format!(
+            "git+--upload-pack=touch {}#python",
+            marker.display()
+        ))
+        .unwrap();
+        assert!(matches!(
+            option.fetch(cache.path()),
+            Err(Error::SourceError(_))
+        ));
+        assert!(!marker.exists());
+
+        let option_ref = GitReference::parse(&format!("git+{}#python@--output=x", url)).unwrap();
+        assert!(matches!(
+            option_ref.fetch(cache.path()),
+            Err(Error::SourceError(_))
+        ));
+
+        Ok(())
+    }
+}
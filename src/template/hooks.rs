@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tera::Context;
+
+use super::source::Template;
+use super::values::{parse_set, Values};
+use crate::error::{Error, Result};
+
+/// How long a hook may run when the manifest does not say
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 60;
+
+/// Commands a template runs around generation, declared in the manifest:
+///
+/// ```toml
+/// [hooks]
+/// timeout = 30
+/// pre = [{ script = "hooks/validate.sh" }]
+/// post = ["git init -q", "git add -A", "git commit -qm 'Initial commit'"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Seconds each hook may run before it is killed
+    pub timeout: Option<u64>,
+    /// Run before rendering, in the current directory. Lines printed as `name=value` are
+    /// added to the answers.
+    #[serde(default)]
+    pub pre: Vec<Hook>,
+    /// Run after the project is written, inside the project directory
+    #[serde(default)]
+    pub post: Vec<Hook>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Hook {
+    /// A shell command, run with `sh -c`
+    Command(String),
+    /// A script shipped with the template. Scripts are not copied into the project.
+    Script { script: PathBuf },
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hook::Command(command) => write!(f, "{}", command),
+            Hook::Script { script } => write!(f, "{}", script.display()),
+        }
+    }
+}
+
+/// When a hook runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    Pre,
+    Post,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookStage::Pre => write!(f, "pre"),
+            HookStage::Post => write!(f, "post"),
+        }
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre.is_empty() && self.post.is_empty()
+    }
+
+    pub fn stage(&self, stage: HookStage) -> &[Hook] {
+        match stage {
+            HookStage::Pre => &self.pre,
+            HookStage::Post => &self.post,
+        }
+    }
+
+    /// Template files used as hook scripts
+    pub fn scripts(&self) -> impl Iterator<Item = &Path> {
+        self.pre
+            .iter()
+            .chain(&self.post)
+            .filter_map(|hook| match hook {
+                Hook::Script { script } => Some(script.as_path()),
+                Hook::Command(_) => None,
+            })
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT))
+    }
+}
+
+/// Environment passed to hooks: every answer as `ESSEX_<NAME>`, plus `ESSEX_PROJECT_DIR`
+pub fn hook_env(context: &Context, project_dir: &Path) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    if let Value::Object(values) = context.clone().into_json() {
+        for (name, value) in values {
+            let value = match value {
+                Value::String(s) => s,
+                other => other.to_string(),
+            };
+            env.push((format!("ESSEX_{}", name.to_uppercase()), value));
+        }
+    }
+
+    let project_dir = std::env::current_dir()
+        .map(|cwd| cwd.join(project_dir))
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    env.push((
+        "ESSEX_PROJECT_DIR".to_string(),
+        project_dir.display().to_string(),
+    ));
+    env
+}
+
+/// Run a template's hooks for one stage in `dir`, stopping at the first one that fails or
+/// times out.
+///
+/// Returns the values pre hooks printed as `name=value` lines; anything else they print is
+/// passed through to stderr. Post hooks print to stderr too, so that stdout only carries
+/// essex's own output, such as `--format json` reports.
+pub fn run_hooks(
+    template: &Template,
+    stage: HookStage,
+    env: &[(String, String)],
+    dir: &Path,
+) -> Result<Values> {
+    let hooks = &template.manifest.hooks;
+    let scripts_dir = tempfile::tempdir()?;
+    let mut values = Values::new();
+
+    for hook in hooks.stage(stage) {
+        let mut command = match hook {
+            Hook::Command(command) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            }
+            Hook::Script { script } => script_command(template, script, scripts_dir.path())?,
+        };
+        command
+            .current_dir(dir)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(match stage {
+                HookStage::Pre => Stdio::piped(),
+                HookStage::Post => std::io::stderr().into(),
+            });
+
+        let output = run_with_timeout(command, hooks.timeout())
+            .map_err(|e| Error::HookError(format!("{} hook '{}': {}", stage, hook, e)))?;
+
+        for line in output.lines() {
+            match parse_set(line) {
+                Ok((name, value)) if is_identifier(&name) => {
+                    values.insert(name, value);
+                }
+                _ => eprintln!("{}", line),
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+/// Write a hook script to `dir` and build the command that runs it: directly when it has a
+/// shebang, with `sh` otherwise
+fn script_command(template: &Template, script: &Path, dir: &Path) -> Result<Command> {
+    let file = template
+        .hook_files
+        .iter()
+        .find(|f| f.path == script)
+        .ok_or_else(|| {
+            Error::InvalidTemplate(format!("hook script '{}' not found", script.display()))
+        })?;
+
+    let name = script
+        .file_name()
+        .ok_or_else(|| Error::InvalidPath(script.display().to_string()))?;
+    let path = dir.join(name);
+    std::fs::write(&path, &file.contents)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700))?;
+        if file.contents.starts_with(b"#!") {
+            return Ok(Command::new(path));
+        }
+    }
+
+    let mut command = Command::new("sh");
+    command.arg(path);
+    Ok(command)
+}
+
+/// Run a command, killing it once `timeout` has passed. Returns what it wrote to stdout.
+fn run_with_timeout(
+    mut command: Command,
+    timeout: Duration,
+) -> std::result::Result<String, String> {
+    let mut child = command.spawn().map_err(|e| e.to_string())?;
+
+    // Read stdout on a thread so a chatty hook cannot fill the pipe and stall
+    let reader = child.stdout.take().map(|mut stdout| {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        })
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {}s", timeout.as_secs()));
+            }
+            None => std::thread::sleep(Duration::from_millis(20)),
+        }
+    };
+
+    let output = match reader {
+        Some(reader) => reader
+            .join()
+            .map_err(|_| "failed to read output".to_string())?
+            .map_err(|e| e.to_string())?,
+        None => String::new(),
+    };
+
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+    Ok(output)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::template::source::{TemplateFile, TemplateSource};
+    use tempfile::tempdir;
+
+    fn template(manifest: &str, files: &[(&str, &str)]) -> Template {
+        let mut files: Vec<TemplateFile> = files
+            .iter()
+            .map(|(path, contents)| TemplateFile {
+                path: PathBuf::from(path),
+                contents: contents.as_bytes().to_vec(),
+                mode: None,
+            })
+            .collect();
+        files.push(TemplateFile {
+            path: PathBuf::from("essex.toml"),
+            contents: manifest.as_bytes().to_vec(),
+            mode: None,
+        });
+        Template::new("hooked", TemplateSource::Builtin, files).unwrap()
+    }
+
+    fn env() -> Vec<(String, String)> {
+        let mut context = Context::new();
+        context.insert("image_name", "app");
+        context.insert("port", &8080);
+        hook_env(&context, Path::new("acme/app"))
+    }
+
+    #[test]
+    fn test_hook_env() {
+        let env = env();
+        assert!(env.contains(&("ESSEX_IMAGE_NAME".to_string(), "app".to_string())));
+        assert!(env.contains(&("ESSEX_PORT".to_string(), "8080".to_string())));
+        assert!(env
+            .iter()
+            .any(|(k, v)| k == "ESSEX_PROJECT_DIR" && v.ends_with("acme/app")));
+    }
+
+    #[test]
+    fn test_run_hooks() -> Result<()> {
+        let template = template(
+            r#"
+[hooks]
+pre = ["echo checking; echo image_tag=$ESSEX_IMAGE_NAME:$ESSEX_PORT", { script = "hooks/derive.sh" }]
+post = ["echo $ESSEX_IMAGE_NAME > hooked.txt"]
+"#,
+            &[
+                ("hooks/derive.sh", "#!/bin/sh\necho replicas=3\n"),
+                ("README.md", "# {{ image_name }}"),
+            ],
+        );
+        let paths: Vec<_> = template.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("README.md")]);
+
+        let dir = tempdir()?;
+        let values = run_hooks(&template, HookStage::Pre, &env(), dir.path())?;
+        assert_eq!(values["image_tag"], Value::from("app:8080"));
+        assert_eq!(values["replicas"], Value::from("3"));
+        assert_eq!(values.len(), 2);
+
+        run_hooks(&template, HookStage::Post, &env(), dir.path())?;
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("hooked.txt"))?,
+            "app\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_and_slow_hooks() -> Result<()> {
+        let dir = tempdir()?;
+
+        let failing = template("[hooks]\npre = [\"exit 3\"]\n", &[]);
+        let err = run_hooks(&failing, HookStage::Pre, &env(), dir.path()).unwrap_err();
+        assert!(matches!(err, Error::HookError(_)));
+        assert!(err.to_string().contains("exit 3"), "{}", err);
+
+        let slow = template("[hooks]\ntimeout = 1\npost = [\"sleep 10\"]\n", &[]);
+        let started = Instant::now();
+        let err = run_hooks(&slow, HookStage::Post, &env(), dir.path()).unwrap_err();
+        assert!(err.to_string().contains("timed out after 1s"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_hook_script() {
+        let files = vec![TemplateFile {
+            path: PathBuf::from("essex.toml"),
+            contents: b"[hooks]\npost = [{ script = \"hooks/missing.sh\" }]\n".to_vec(),
+            mode: None,
+        }];
+        let result = Template::new("hooked", TemplateSource::Builtin, files);
+        assert!(matches!(result, Err(Error::InvalidTemplate(_))));
+    }
+}
@@ -0,0 +1,452 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::PathBuf;
+use tera::ast::{Expr, ExprVal, Node};
+use tera::Tera;
+
+use super::manifest::VariableType;
+use super::{
+    file_mode, registration_error, template_name, template_text, Template, DEFAULT_USERNAME,
+    DEFAULT_VENDOR,
+};
+use crate::error::Result;
+
+/// What a template takes and produces, worked out without rendering it
+#[derive(Debug, Clone, Serialize)]
+pub struct Inspection {
+    pub variables: Vec<InspectedVariable>,
+    pub files: Vec<InspectedFile>,
+}
+
+/// A variable a template declares or uses
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectedVariable {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: VariableType,
+    pub description: Option<String>,
+    pub default: Option<Value>,
+    /// Provided by every project, such as `image_name`
+    pub builtin: bool,
+    /// Declared in the template's manifest
+    pub declared: bool,
+    /// Used by a file, a file name or an `include_if` condition
+    pub used: bool,
+}
+
+/// A file a template produces
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectedFile {
+    /// Path inside the template, before its name is rendered
+    pub path: PathBuf,
+    /// Octal permissions, such as `0644`
+    pub mode: String,
+    pub executable: bool,
+    /// Whether the file is rendered, rather than copied as it is
+    pub rendered: bool,
+    /// `include_if` conditions the file is only generated under
+    pub conditions: Vec<String>,
+}
+
+/// Describe a template's variables and files, with its text files registered on a copy of
+/// `registry` so that shared partials and base templates are followed
+pub fn inspect(registry: &Tera, template: &Template) -> Result<Inspection> {
+    let manifest = &template.manifest;
+    let mut tera = registry.clone();
+
+    let mut sources = Vec::new();
+    let mut files = Vec::new();
+    for file in &template.files {
+        let Some(conditions) = manifest.conditions(&file.path)? else {
+            continue;
+        };
+        let text = template_text(manifest, file)?;
+        if let Some(content) = text {
+            sources.push((file.path.as_path(), content));
+        }
+
+        let mode = file_mode(manifest, file, &file.path)?;
+        files.push(InspectedFile {
+            path: file.path.clone(),
+            mode: format!("{:04o}", mode),
+            executable: mode & 0o111 != 0,
+            rendered: text.is_some(),
+            conditions: conditions
+                .into_iter()
+                .map(|(_, condition)| condition.to_string())
+                .collect(),
+        });
+    }
+    tera.add_raw_templates(
+        sources
+            .iter()
+            .map(|(path, content)| (template_name(path), *content)),
+    )
+    .map_err(|e| registration_error(sources.iter().copied(), e).in_template(&template.name))?;
+
+    let mut references = References::new(&tera);
+    for (path, _) in &sources {
+        references.template(&template_name(path));
+    }
+    for file in &template.files {
+        for segment in file.path.iter() {
+            references.text(&segment.to_string_lossy());
+        }
+    }
+    for condition in manifest.include_if.values() {
+        references.text(&format!("{{% if {} %}}{{% endif %}}", condition));
+    }
+    let used: BTreeSet<String> = references
+        .found
+        .union(&references.guarded)
+        .cloned()
+        .collect();
+
+    let mut variables = BTreeMap::new();
+    for (name, description, default) in builtin_variables() {
+        if used.contains(name) || manifest.variable(name).is_some() {
+            variables.insert(
+                name.to_string(),
+                InspectedVariable {
+                    name: name.to_string(),
+                    kind: VariableType::String,
+                    description: Some(description.to_string()),
+                    default,
+                    builtin: true,
+                    declared: false,
+                    used: used.contains(name),
+                },
+            );
+        }
+    }
+    for variable in &manifest.variables {
+        let builtin = variables.contains_key(&variable.name);
+        let inspected =
+            variables
+                .entry(variable.name.clone())
+                .or_insert_with(|| InspectedVariable {
+                    name: variable.name.clone(),
+                    kind: variable.kind,
+                    description: None,
+                    default: None,
+                    builtin,
+                    declared: true,
+                    used: used.contains(&variable.name),
+                });
+        inspected.kind = variable.kind;
+        inspected.declared = true;
+        if variable.description.is_some() {
+            inspected.description = variable.description.clone();
+        }
+        if variable.default.is_some() {
+            inspected.default = variable.default.clone();
+        }
+    }
+    for name in &used {
+        variables
+            .entry(name.clone())
+            .or_insert_with(|| InspectedVariable {
+                name: name.clone(),
+                kind: VariableType::String,
+                description: None,
+                default: None,
+                builtin: false,
+                declared: false,
+                used: true,
+            });
+    }
+
+    Ok(Inspection {
+        variables: variables.into_values().collect(),
+        files,
+    })
+}
+
+/// The built-in variables with a description and their default, if they have a fixed one
+fn builtin_variables() -> Vec<(&'static str, &'static str, Option<Value>)> {
+    vec![
+        (
+            "repo_username",
+            "Username for the project",
+            Some(DEFAULT_USERNAME.into()),
+        ),
+        ("repo_namespace", "Namespace part of the project name", None),
+        ("image_name", "Project part of the project name", None),
+        (
+            "vendor",
+            "Vendor name for the project",
+            Some(DEFAULT_VENDOR.into()),
+        ),
+        ("version", "Version of the project", Some("0.1.0".into())),
+        ("build_date", "Time the project is generated", None),
+        ("vcs_ref", "Version control reference", Some("HEAD".into())),
+    ]
+}
+
+/// Collects the context variables templates read, following includes, parent templates and
+/// imported macros. Loop variables, macro arguments and `set` variables are not context
+/// variables and are left out.
+pub(super) struct References<'a> {
+    tera: &'a Tera,
+    /// Variables read as they are, which must be in the context
+    pub found: BTreeSet<String>,
+    /// Variables only read through the `default` filter or tested with `is defined`
+    pub guarded: BTreeSet<String>,
+    visited: HashSet<String>,
+    scopes: Vec<HashSet<String>>,
+}
+
+impl<'a> References<'a> {
+    pub fn new(tera: &'a Tera) -> Self {
+        Self {
+            tera,
+            found: BTreeSet::new(),
+            guarded: BTreeSet::new(),
+            visited: HashSet::new(),
+            scopes: vec![HashSet::new()],
+        }
+    }
+
+    /// Walk a registered template, once
+    pub fn template(&mut self, name: &str) {
+        if !self.visited.insert(name.to_string()) {
+            return;
+        }
+        if let Ok(template) = self.tera.get_template(name) {
+            self.scopes.push(HashSet::new());
+            self.nodes(&template.ast);
+            self.scopes.pop();
+        }
+    }
+
+    /// Walk a piece of template text that is not registered, such as a file name
+    pub fn text(&mut self, text: &str) {
+        if !text.contains('{') {
+            return;
+        }
+        if let Ok(template) = tera::Template::new("__inspect", None, text) {
+            self.nodes(&template.ast);
+        }
+    }
+
+    fn scoped(&mut self, locals: impl IntoIterator<Item = String>, body: &[Node]) {
+        self.scopes.push(locals.into_iter().collect());
+        self.nodes(body);
+        self.scopes.pop();
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::VariableBlock(_, expr) => self.expr(expr),
+            Node::MacroDefinition(_, definition, _) => {
+                for default in definition.args.values().flatten() {
+                    self.expr(default);
+                }
+                self.scoped(definition.args.keys().cloned(), &definition.body);
+            }
+            Node::Extends(_, name) => self.template(name),
+            Node::Include(_, names, _) => {
+                for name in names {
+                    self.template(name);
+                }
+            }
+            Node::ImportMacro(_, name, _) => self.template(name),
+            Node::Set(_, set) => {
+                self.expr(&set.value);
+                let scope = if set.global {
+                    self.scopes.first_mut()
+                } else {
+                    self.scopes.last_mut()
+                };
+                if let Some(scope) = scope {
+                    scope.insert(set.key.clone());
+                }
+            }
+            Node::FilterSection(_, section, _) => {
+                self.exprs(section.filter.args.values());
+                self.nodes(&section.body);
+            }
+            Node::Block(_, block, _) => self.nodes(&block.body),
+            Node::Forloop(_, forloop, _) => {
+                self.expr(&forloop.container);
+                let mut locals = vec![forloop.value.clone(), "loop".to_string()];
+                locals.extend(forloop.key.clone());
+                self.scoped(locals, &forloop.body);
+                if let Some(empty) = &forloop.empty_body {
+                    self.nodes(empty);
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, expr, body) in &condition.conditions {
+                    self.expr(expr);
+                    self.nodes(body);
+                }
+                if let Some((_, body)) = &condition.otherwise {
+                    self.nodes(body);
+                }
+            }
+            Node::Super
+            | Node::Text(_)
+            | Node::Raw(..)
+            | Node::Break(_)
+            | Node::Continue(_)
+            | Node::Comment(..) => {}
+        }
+    }
+
+    fn exprs<'e>(&mut self, exprs: impl IntoIterator<Item = &'e Expr>) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.val {
+            ExprVal::Ident(ident) if expr.has_default_filter() => self.ident(ident, true),
+            value => self.value(value),
+        }
+        for filter in &expr.filters {
+            self.exprs(filter.args.values());
+        }
+    }
+
+    fn value(&mut self, value: &ExprVal) {
+        match value {
+            ExprVal::Ident(ident) => self.ident(ident, false),
+            ExprVal::Math(math) => {
+                self.expr(&math.lhs);
+                self.expr(&math.rhs);
+            }
+            ExprVal::Logic(logic) => {
+                self.expr(&logic.lhs);
+                self.expr(&logic.rhs);
+            }
+            ExprVal::Test(test) => {
+                let guarded = matches!(test.name.as_str(), "defined" | "undefined");
+                self.ident(&test.ident, guarded);
+                self.exprs(&test.args);
+            }
+            ExprVal::MacroCall(call) => self.exprs(call.args.values()),
+            ExprVal::FunctionCall(call) => self.exprs(call.args.values()),
+            ExprVal::Array(items) => self.exprs(items),
+            ExprVal::StringConcat(concat) => {
+                for value in &concat.values {
+                    self.value(value);
+                }
+            }
+            ExprVal::In(check) => {
+                self.expr(&check.lhs);
+                self.expr(&check.rhs);
+            }
+            ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => {}
+        }
+    }
+
+    /// Record the variable an identifier such as `user.name` or `ports[0]` starts with
+    fn ident(&mut self, ident: &str, guarded: bool) {
+        let name = ident.split(['.', '[']).next().unwrap_or_default();
+        if name.is_empty()
+            || name == "__tera_context"
+            || self.scopes.iter().any(|scope| scope.contains(name))
+        {
+            return;
+        }
+        if guarded {
+            self.guarded.insert(name.to_string());
+        } else {
+            self.found.insert(name.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{TemplateFile, TemplateSource};
+
+    fn file(path: &str, contents: &str) -> TemplateFile {
+        TemplateFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let mut registry = Tera::default();
+        registry
+            .add_raw_template("_partials/labels", "LABEL vendor={{ vendor }} {{ team }}")
+            .unwrap();
+        let files = vec![
+            file(
+                "essex.toml",
+                "[include_if]\n\"compose.yml\" = \"with_compose\"\n\n\
+                 [[variables]]\nname = \"port\"\ntype = \"integer\"\ndefault = 8080\n\n\
+                 [[variables]]\nname = \"unused\"\ndescription = \"Not used\"\n",
+            ),
+            file(
+                "Dockerfile",
+                "FROM {{ base | default(value='alpine') }}\n\
+                 {% include \"_partials/labels\" %}\n\
+                 {% set tag = image_name ~ ':' ~ version %}{{ tag }}\n\
+                 {% for p in extra_ports %}EXPOSE {{ p }} {{ loop.index }}{% endfor %}\n\
+                 {% if port > 1024 and user.name %}USER app{% endif %}\n",
+            ),
+            file("src/{{ package }}/run.sh", "#!/bin/sh\n"),
+            file("compose.yml", "services: {}\n"),
+        ];
+        let template = Template::new("svc", TemplateSource::Builtin, files)?;
+        let inspection = inspect(&registry, &template)?;
+
+        let names: Vec<&str> = inspection
+            .variables
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "base",
+                "extra_ports",
+                "image_name",
+                "package",
+                "port",
+                "team",
+                "unused",
+                "user",
+                "vendor",
+                "version",
+                "with_compose",
+            ]
+        );
+
+        let variable = |name: &str| {
+            inspection
+                .variables
+                .iter()
+                .find(|v| v.name == name)
+                .unwrap()
+        };
+        assert_eq!(variable("port").default, Some(8080.into()));
+        assert!(variable("port").declared && variable("port").used);
+        assert!(!variable("unused").used);
+        assert_eq!(variable("vendor").default, Some(DEFAULT_VENDOR.into()));
+        assert!(variable("vendor").builtin && !variable("vendor").declared);
+        assert!(!variable("base").declared);
+
+        let script = &inspection.files[2];
+        assert_eq!(script.path, PathBuf::from("src/{{ package }}/run.sh"));
+        assert!(script.executable);
+        assert_eq!(script.mode, "0755");
+        assert_eq!(inspection.files[1].conditions, vec!["with_compose"]);
+        Ok(())
+    }
+}
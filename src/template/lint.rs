@@ -0,0 +1,438 @@
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tera::Tera;
+
+use super::inspect::References;
+use super::manifest::MANIFEST_FILE;
+use super::source::utf8_diagnostic;
+use super::{
+    name_position, registration_error, render_diagnostic, template_name, Template, TemplateFile,
+    BUILTIN_VARIABLES,
+};
+use crate::error::{Diagnostic, Error, Result};
+
+/// How serious a lint finding is: errors break generation or the generated project, warnings
+/// are likely mistakes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a template
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Check that found the problem, such as `shebang`
+    pub rule: &'static str,
+    #[serde(flatten)]
+    pub diagnostic: Diagnostic,
+}
+
+impl Finding {
+    fn new(severity: Severity, rule: &'static str, diagnostic: Diagnostic) -> Self {
+        Self {
+            severity,
+            rule,
+            diagnostic,
+        }
+    }
+
+    /// The finding as printed on a terminal, with the offending line when it is known
+    pub fn render(&self) -> String {
+        self.diagnostic
+            .render_as(&format!("{}[{}]", self.severity, self.rule))
+    }
+}
+
+/// Check every file of a template, with the shared templates of `registry` available to
+/// include and extend:
+///
+/// - `syntax`: files Tera cannot parse
+/// - `undefined-variable`: variables that are neither built in nor declared in the manifest
+/// - `non-utf8`: text files that are not UTF-8, which are copied without being rendered
+/// - `shebang`: `.sh` files that do not start with `#!`
+/// - `makefile-tabs`: Makefile recipe lines indented with spaces
+/// - `docker-format`: Go template strings, as given to `docker --format`, outside
+///   `{% raw %}`
+pub fn lint(registry: &Tera, template: &Template) -> Result<Vec<Finding>> {
+    let manifest = &template.manifest;
+    let mut findings = Vec::new();
+    let mut sources = Vec::new();
+
+    for file in &template.files {
+        let text = match std::str::from_utf8(&file.contents) {
+            Ok(text) => text,
+            Err(_) if file.is_binary() && has_nul(file) => continue,
+            Err(e) => {
+                let mut diagnostic = utf8_diagnostic(&template.name, file, e.valid_up_to());
+                diagnostic
+                    .message
+                    .push_str(", so the file is copied without rendering");
+                findings.push(Finding::new(Severity::Warning, "non-utf8", diagnostic));
+                continue;
+            }
+        };
+        let located = |line: usize, column: usize, message: String| Diagnostic {
+            template: Some(template.name.clone()),
+            file: file.path.clone(),
+            line: Some(line),
+            column: Some(column),
+            message,
+            snippet: text.lines().nth(line - 1).map(str::to_string),
+        };
+
+        if file.path.extension().is_some_and(|ext| ext == "sh") && !text.starts_with("#!") {
+            let message = "script does not start with a shebang such as #!/bin/sh".to_string();
+            findings.push(Finding::new(
+                Severity::Error,
+                "shebang",
+                located(1, 1, message),
+            ));
+        }
+
+        if is_makefile(&file.path) {
+            for line in space_indented_recipes(text) {
+                let message = "recipe line is indented with spaces; make needs a tab".to_string();
+                findings.push(Finding::new(
+                    Severity::Error,
+                    "makefile-tabs",
+                    located(line, 1, message),
+                ));
+            }
+        }
+
+        if manifest.copies(&file.path)? {
+            continue;
+        }
+
+        let go_templates = go_template_positions(text);
+        for &(line, column) in &go_templates {
+            let message = "looks like a Go template for docker --format; wrap it in \
+                           {% raw %}...{% endraw %} so that Tera leaves it alone"
+                .to_string();
+            findings.push(Finding::new(
+                Severity::Error,
+                "docker-format",
+                located(line, column, message),
+            ));
+        }
+
+        let name = template_name(&file.path);
+        match tera::Template::new(&name, None, text) {
+            Ok(_) => sources.push((file.path.as_path(), text)),
+            Err(e) => {
+                // Worded as when the template is registered, which nests the position
+                let e = tera::Error::chain(format!("Failed to parse '{}'", name), e);
+                let mut diagnostic = render_diagnostic(&file.path, Some(text), e);
+                // A Go template on the same line is the cause, and is already reported
+                if go_templates
+                    .iter()
+                    .any(|(line, _)| Some(*line) == diagnostic.line)
+                {
+                    continue;
+                }
+                diagnostic.template = Some(template.name.clone());
+                findings.push(Finding::new(Severity::Error, "syntax", diagnostic));
+            }
+        }
+    }
+
+    let mut tera = registry.clone();
+    if let Err(e) = tera.add_raw_templates(
+        sources
+            .iter()
+            .map(|(path, content)| (template_name(path), *content)),
+    ) {
+        let diagnostic = match registration_error(sources.iter().copied(), e) {
+            Error::Diagnostic(diagnostic) => *diagnostic,
+            error => unlocated(template, named_file(&error.to_string(), &sources), error),
+        };
+        findings.push(Finding::new(
+            Severity::Error,
+            "syntax",
+            Diagnostic {
+                template: Some(template.name.clone()),
+                ..diagnostic
+            },
+        ));
+    }
+
+    let declared =
+        |name: &str| BUILTIN_VARIABLES.contains(&name) || manifest.variable(name).is_some();
+    let undefined = |name: &str| {
+        format!(
+            "`{}` is not a built-in variable and is not declared in {}",
+            name, MANIFEST_FILE
+        )
+    };
+    let mut reported = BTreeSet::new();
+    for (path, text) in &sources {
+        let mut references = References::new(&tera);
+        references.template(&template_name(path));
+        for name in references.found.iter().filter(|name| !declared(name)) {
+            let position = name_position(text, name);
+            if position.is_none() && !reported.insert(name.clone()) {
+                continue;
+            }
+            findings.push(Finding::new(
+                Severity::Error,
+                "undefined-variable",
+                Diagnostic {
+                    template: Some(template.name.clone()),
+                    file: path.to_path_buf(),
+                    line: position.map(|(line, _)| line),
+                    column: position.map(|(_, column)| column),
+                    message: undefined(name),
+                    snippet: position
+                        .and_then(|(line, _)| text.lines().nth(line - 1))
+                        .map(str::to_string),
+                },
+            ));
+        }
+    }
+
+    // File names and include_if conditions are templates too
+    let mut checks: Vec<(PathBuf, String)> = template
+        .files
+        .iter()
+        .map(|file| (file.path.clone(), file.path.to_string_lossy().into_owned()))
+        .collect();
+    checks.extend(manifest.include_if.values().map(|condition| {
+        (
+            PathBuf::from(MANIFEST_FILE),
+            format!("{{% if {} %}}{{% endif %}}", condition),
+        )
+    }));
+    for (path, text) in checks {
+        let mut references = References::new(&tera);
+        references.text(&text);
+        for name in references.found.iter().filter(|name| !declared(name)) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "undefined-variable",
+                Diagnostic {
+                    template: Some(template.name.clone()),
+                    file: path.clone(),
+                    line: None,
+                    column: None,
+                    message: undefined(name),
+                    snippet: None,
+                },
+            ));
+        }
+    }
+
+    Ok(findings)
+}
+
+/// A NUL byte near the start, which marks a file as binary rather than text in another
+/// encoding
+fn has_nul(file: &TemplateFile) -> bool {
+    file.contents[..file.contents.len().min(8000)].contains(&0)
+}
+
+fn is_makefile(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    matches!(name.as_ref(), "Makefile" | "makefile" | "GNUmakefile") || name.ends_with(".mk")
+}
+
+/// Lines of a Makefile's recipes that start with spaces instead of a tab
+fn space_indented_recipes(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut in_recipe = false;
+    let mut in_define = false;
+    let mut continued = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let was_continued = continued;
+        continued = line.ends_with('\\');
+        if was_continued || line.starts_with('\t') || line.trim().is_empty() {
+            continue;
+        }
+
+        let directive = line.split_whitespace().next().unwrap_or_default();
+        if in_define {
+            in_define = directive != "endef";
+            continue;
+        }
+        if line.starts_with(' ') {
+            if in_recipe && !line.trim_start().starts_with('#') {
+                lines.push(index + 1);
+            }
+            continue;
+        }
+
+        in_define = directive == "define";
+        in_recipe = is_rule(line);
+    }
+    lines
+}
+
+/// A rule such as `build: deps`, as opposed to a variable assignment such as `X := y`
+fn is_rule(line: &str) -> bool {
+    if line.starts_with('#') {
+        return false;
+    }
+    match line.find(':') {
+        Some(colon) => !line[..colon].contains('=') && !line[colon + 1..].starts_with('='),
+        None => false,
+    }
+}
+
+/// Line and column of each Go template in `text`, such as `{{.Repository}}` or
+/// `{{json .Config}}`, that is not inside a `{% raw %}` block. Tera cannot parse them.
+fn go_template_positions(text: &str) -> Vec<(usize, usize)> {
+    static GO_TEMPLATE: OnceLock<Regex> = OnceLock::new();
+    static RAW: OnceLock<Regex> = OnceLock::new();
+    let go_template = GO_TEMPLATE.get_or_init(|| {
+        Regex::new(
+            r"\{\{-?\s*(?:(?:json|index|range|printf|println|join|if|with|len|table|upper|lower|title|split)\s+)?\.",
+        )
+        .expect("the Go template pattern is valid")
+    });
+    let raw = RAW.get_or_init(|| {
+        Regex::new(r"(?s)\{%-?\s*raw\s*-?%\}.*?(?:\{%-?\s*endraw\s*-?%\}|\z)")
+            .expect("the raw block pattern is valid")
+    });
+
+    let raw_blocks: Vec<Range<usize>> = raw.find_iter(text).map(|m| m.range()).collect();
+    go_template
+        .find_iter(text)
+        .filter(|m| !raw_blocks.iter().any(|block| block.contains(&m.start())))
+        .map(|m| {
+            let before = &text[..m.start()];
+            let line = before.matches('\n').count() + 1;
+            let start = before.rfind('\n').map_or(0, |newline| newline + 1);
+            (line, before[start..].chars().count() + 1)
+        })
+        .collect()
+}
+
+/// The template file named in quotes in a Tera error, such as the child template in
+/// "Template 'Dockerfile' is inheriting from '_base/Dockerfile', which doesn't exist"
+fn named_file(message: &str, sources: &[(&Path, &str)]) -> PathBuf {
+    sources
+        .iter()
+        .find(|(path, _)| message.contains(&format!("'{}'", template_name(path))))
+        .map_or_else(PathBuf::new, |(path, _)| path.to_path_buf())
+}
+
+fn unlocated(template: &Template, file: PathBuf, error: Error) -> Diagnostic {
+    Diagnostic {
+        template: Some(template.name.clone()),
+        file,
+        line: None,
+        column: None,
+        message: match error {
+            Error::TemplateError(message) => message,
+            error => error.to_string(),
+        },
+        snippet: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::TemplateSource;
+
+    fn file(path: &str, contents: &[u8]) -> TemplateFile {
+        TemplateFile {
+            path: PathBuf::from(path),
+            contents: contents.to_vec(),
+            mode: None,
+        }
+    }
+
+    fn lint_files(files: Vec<TemplateFile>) -> Vec<(&'static str, String, Option<usize>)> {
+        let template = Template::new("svc", TemplateSource::Builtin, files).unwrap();
+        lint(&Tera::default(), &template)
+            .unwrap()
+            .into_iter()
+            .map(|f| {
+                (
+                    f.rule,
+                    f.diagnostic.file.display().to_string(),
+                    f.diagnostic.line,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_clean_template() {
+        let findings = lint_files(vec![
+            file(
+                "essex.toml",
+                b"[[variables]]\nname = \"port\"\ndefault = 8080\n",
+            ),
+            file("run.sh", b"#!/bin/sh\nexec app --port {{ port }}\n"),
+            file(
+                "Makefile",
+                b"IMAGE := {{ image_name }}\n\nbuild: deps\n\tdocker build -t $(IMAGE) .\n\n\
+                  clean:\n\tdocker images --format=\"{%raw%}{{.Repository}}{%endraw%}\"\n",
+            ),
+            file("logo.png", b"\x89PNG\r\n\x1a\n\x00\x00"),
+        ]);
+        assert_eq!(findings, vec![]);
+    }
+
+    #[test]
+    fn test_lint_findings() {
+        let findings = lint_files(vec![
+            file("Dockerfile", b"FROM scratch\nLABEL a={{ a | }}\n"),
+            file("README.md", b"# {{ image_name }}\n\nBy {{ team }}\n"),
+            file("run.sh", b"exec app\n"),
+            file(
+                "Makefile",
+                b"build:\n\tdocker build .\n    docker push\n\nX = 1\n    continued\n",
+            ),
+            file(
+                "ps.mk",
+                b"ps:\n\tdocker ps --format '{{.Names}}'\n\t{{ json .Config }}\n",
+            ),
+            file("latin1.txt", b"caf\xe9 {{ image_name }}\n"),
+            file("src/{{ package }}/main.py", b"print()\n"),
+        ]);
+        assert_eq!(
+            findings,
+            vec![
+                ("syntax", "Dockerfile".to_string(), Some(2)),
+                ("makefile-tabs", "Makefile".to_string(), Some(3)),
+                ("non-utf8", "latin1.txt".to_string(), Some(1)),
+                ("docker-format", "ps.mk".to_string(), Some(2)),
+                ("docker-format", "ps.mk".to_string(), Some(3)),
+                ("shebang", "run.sh".to_string(), Some(1)),
+                ("undefined-variable", "README.md".to_string(), Some(3)),
+                (
+                    "undefined-variable",
+                    "src/{{ package }}/main.py".to_string(),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_space_indented_recipes() {
+        let makefile = "A := 1\n  B\n.PHONY: x\nx:\n\t@echo \\\n    continued\n  bad\n\
+                        define BLOCK\n  text\nendef\n";
+        assert_eq!(space_indented_recipes(makefile), vec![7]);
+    }
+}
@@ -0,0 +1,686 @@
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tera::Context;
+
+use super::compose::{MergeRule, MergeStrategy};
+use super::hooks::Hooks;
+use crate::error::{Error, Result};
+
+/// Name of the manifest file at the root of a template directory
+pub const MANIFEST_FILE: &str = "essex.toml";
+
+/// Template manifest, read from `essex.toml` at the root of a template.
+///
+/// ```toml
+/// description = "Python service"
+/// version = "1.0.0"
+/// exclude = ["**/*.orig"]
+/// copy = [".github/workflows"]
+///
+/// [include_if]
+/// "docker-compose.yml" = "with_compose"
+///
+/// [[modes]]
+/// glob = "runtime-assets/usr/local/bin/*"
+/// mode = "0755"
+///
+/// [[merge]]
+/// glob = "Makefile"
+/// strategy = "append"
+///
+/// [[variables]]
+/// name = "python_version"
+/// description = "Python version for the base image"
+/// default = "3.12"
+/// choices = ["3.11", "3.12"]
+///
+/// [[variables]]
+/// name = "port"
+/// type = "integer"
+/// default = 8080
+///
+/// [[variables]]
+/// name = "with_compose"
+/// type = "bool"
+/// default = false
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub description: Option<String>,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+    /// Globs of template files that are never generated
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Globs of template files that are only generated when a condition holds. Conditions
+    /// are Tera expressions, such as `with_compose` or `database == "postgres"`.
+    #[serde(default)]
+    pub include_if: BTreeMap<String, String>,
+    /// Globs of text files copied as they are instead of rendered, for files that use `{{`
+    /// for other tools such as Helm charts or GitHub Actions workflows
+    #[serde(default)]
+    pub copy: Vec<String>,
+    /// Permissions for generated files and directories. When several rules match, the
+    /// last one wins.
+    #[serde(default)]
+    pub modes: Vec<ModeRule>,
+    /// How this template combines its files with those of templates layered below it. When
+    /// several rules match, the last one wins.
+    #[serde(default)]
+    pub merge: Vec<MergeRule>,
+    /// Commands run before and after generation
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// The globs of the rules above, compiled once by [`Manifest::parse`]
+    #[serde(skip)]
+    globs: Globs,
+}
+
+/// Compiled globs of a manifest's rules, in the order of the rules
+#[derive(Debug, Clone, Default)]
+struct Globs {
+    exclude: Vec<GlobMatcher>,
+    include_if: Vec<GlobMatcher>,
+    copy: Vec<GlobMatcher>,
+    modes: Vec<GlobMatcher>,
+    merge: Vec<GlobMatcher>,
+}
+
+impl Globs {
+    fn compile(manifest: &Manifest) -> Result<Self> {
+        Ok(Self {
+            exclude: globs(&manifest.exclude)?,
+            include_if: globs(manifest.include_if.keys())?,
+            copy: globs(&manifest.copy)?,
+            modes: globs(manifest.modes.iter().map(|rule| &rule.glob))?,
+            merge: globs(manifest.merge.iter().map(|rule| &rule.glob))?,
+        })
+    }
+}
+
+/// Permissions for the files and directories matching a glob
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModeRule {
+    pub glob: String,
+    /// Octal mode for matching files, such as `"0600"`
+    pub mode: Option<String>,
+    /// Octal mode for matching directories, such as `"0700"`
+    pub dir_mode: Option<String>,
+}
+
+/// A variable declared by a template
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variable {
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub kind: VariableType,
+    pub default: Option<Value>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub choices: Vec<Value>,
+    /// Regular expression that string values must match in full
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableType {
+    #[default]
+    String,
+    Bool,
+    Integer,
+}
+
+impl std::fmt::Display for VariableType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariableType::String => write!(f, "string"),
+            VariableType::Bool => write!(f, "bool"),
+            VariableType::Integer => write!(f, "integer"),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut manifest: Manifest = toml::from_str(content)
+            .map_err(|e| Error::InvalidTemplate(format!("{}: {}", MANIFEST_FILE, e)))?;
+        manifest.globs = Globs::compile(&manifest)?;
+
+        for rule in &manifest.modes {
+            if rule.mode.is_none() && rule.dir_mode.is_none() {
+                return Err(Error::InvalidTemplate(format!(
+                    "{}: modes entry for '{}' needs a mode or dir_mode",
+                    MANIFEST_FILE, rule.glob
+                )));
+            }
+            for mode in rule.mode.iter().chain(&rule.dir_mode) {
+                parse_mode(mode)?;
+            }
+        }
+
+        for variable in &manifest.variables {
+            variable.regex()?;
+            if let Some(default) = &variable.default {
+                variable.validate(default).map_err(|e| {
+                    Error::InvalidTemplate(format!(
+                        "{}: default for '{}' is invalid: {}",
+                        MANIFEST_FILE, variable.name, e
+                    ))
+                })?;
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|v| v.name == name)
+    }
+
+    /// Merge declared variables into `context`.
+    ///
+    /// Values already in the context are validated and converted to the declared type,
+    /// missing ones fall back to their default.
+    pub fn resolve(&self, mut context: Context) -> Result<Context> {
+        for variable in &self.variables {
+            let value = match context.get(&variable.name) {
+                Some(value) => value.clone(),
+                None => variable
+                    .default
+                    .clone()
+                    .ok_or_else(|| Error::MissingVariable(variable.name.clone()))?,
+            };
+
+            let value = variable.validate(&value)?;
+            context.insert(&variable.name, &value);
+        }
+
+        Ok(context)
+    }
+
+    /// Check a template file against the `exclude` and `include_if` rules.
+    ///
+    /// Globs match the file or any directory above it, so `runtime-assets` covers everything
+    /// inside that directory. `*` does not cross directories; use `**` for that. Conditions
+    /// are evaluated with `render`.
+    pub fn includes(
+        &self,
+        path: &Path,
+        mut render: impl FnMut(&str) -> tera::Result<String>,
+    ) -> Result<bool> {
+        let Some(conditions) = self.conditions(path)? else {
+            return Ok(false);
+        };
+
+        for (pattern, condition) in conditions {
+            let rendered =
+                render(&format!("{{% if {} %}}true{{% endif %}}", condition)).map_err(|e| {
+                    super::render_error(
+                        Path::new(MANIFEST_FILE),
+                        None,
+                        tera::Error::chain(format!("include_if for '{}'", pattern), e),
+                    )
+                })?;
+            if rendered != "true" {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The `include_if` globs matching a template file with their conditions, or `None` when
+    /// an `exclude` glob leaves the file out
+    pub fn conditions(&self, path: &Path) -> Result<Option<Vec<(&str, &str)>>> {
+        if self
+            .globs
+            .exclude
+            .iter()
+            .any(|glob| matches_path(glob, path))
+        {
+            return Ok(None);
+        }
+
+        let conditions = self
+            .include_if
+            .iter()
+            .zip(&self.globs.include_if)
+            .filter(|(_, glob)| matches_path(glob, path))
+            .map(|((pattern, condition), _)| (pattern.as_str(), condition.as_str()))
+            .collect();
+        Ok(Some(conditions))
+    }
+
+    /// Mode declared for a template file by the `modes` rules
+    pub fn file_mode(&self, path: &Path) -> Result<Option<u32>> {
+        self.mode_for(path, |rule| rule.mode.as_deref())
+    }
+
+    /// Mode declared for a template directory by the `modes` rules
+    pub fn dir_mode(&self, path: &Path) -> Result<Option<u32>> {
+        self.mode_for(path, |rule| rule.dir_mode.as_deref())
+    }
+
+    fn mode_for(
+        &self,
+        path: &Path,
+        mode: impl Fn(&ModeRule) -> Option<&str>,
+    ) -> Result<Option<u32>> {
+        let mut found = None;
+        for (rule, glob) in self.modes.iter().zip(&self.globs.modes) {
+            if let Some(value) = mode(rule) {
+                if matches_path(glob, path) {
+                    found = Some(parse_mode(value)?);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// How a generated file is merged with one an earlier layer produced, by the `merge` rules
+    pub fn merge_strategy(&self, path: &Path) -> Result<MergeStrategy> {
+        let mut found = MergeStrategy::default();
+        for (rule, glob) in self.merge.iter().zip(&self.globs.merge) {
+            if matches_path(glob, path) {
+                found = rule.strategy;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Whether a template file matches the `copy` globs and must not be rendered
+    pub fn copies(&self, path: &Path) -> Result<bool> {
+        Ok(self.globs.copy.iter().any(|glob| matches_path(glob, path)))
+    }
+}
+
+/// Parse an octal permission string such as `"0755"`
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| {
+            Error::InvalidTemplate(format!(
+                "{}: '{}' is not an octal file mode",
+                MANIFEST_FILE, mode
+            ))
+        })
+}
+
+fn glob(pattern: &str) -> Result<GlobMatcher> {
+    GlobBuilder::new(pattern.trim_end_matches('/'))
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| Error::InvalidTemplate(format!("{}: {}", MANIFEST_FILE, e)))
+}
+
+fn globs<'a>(patterns: impl IntoIterator<Item = &'a String>) -> Result<Vec<GlobMatcher>> {
+    patterns.into_iter().map(|pattern| glob(pattern)).collect()
+}
+
+fn matches_path(glob: &GlobMatcher, path: &Path) -> bool {
+    path.ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| glob.is_match(p))
+}
+
+impl Variable {
+    /// A string variable with no default, description or constraints
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: VariableType::String,
+            default: None,
+            description: None,
+            choices: Vec::new(),
+            pattern: None,
+        }
+    }
+
+    fn regex(&self) -> Result<Option<Regex>> {
+        self.pattern
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    Error::InvalidTemplate(format!(
+                        "{}: invalid pattern for '{}': {}",
+                        MANIFEST_FILE, self.name, e
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Parse a raw string, as typed on the command line, into a value of this variable's type
+    pub fn parse(&self, raw: &str) -> Result<Value> {
+        self.validate(&Value::String(raw.to_string()))
+    }
+
+    /// Check a value against the declared type, choices and pattern, returning it converted
+    /// to the declared type
+    pub fn validate(&self, value: &Value) -> Result<Value> {
+        let invalid = |reason: String| Error::InvalidVariable(format!("{}: {}", self.name, reason));
+
+        let value = match (self.kind, value) {
+            (VariableType::String, Value::String(_)) => value.clone(),
+            (VariableType::String, Value::Number(_) | Value::Bool(_)) => {
+                Value::String(value.to_string())
+            }
+            (VariableType::Bool, Value::Bool(_)) => value.clone(),
+            (VariableType::Bool, Value::String(s)) => match s.to_lowercase().as_str() {
+                "true" | "yes" | "y" | "1" => Value::Bool(true),
+                "false" | "no" | "n" | "0" => Value::Bool(false),
+                _ => return Err(invalid(format!("'{}' is not a bool", s))),
+            },
+            (VariableType::Integer, Value::Number(n)) if n.is_i64() => value.clone(),
+            (VariableType::Integer, Value::String(s)) => {
+                s.trim()
+                    .parse::<i64>()
+                    .map(Value::from)
+                    .map_err(|_| invalid(format!("'{}' is not an integer", s)))?
+            }
+            _ => return Err(invalid(format!("expected a {}, got {}", self.kind, value))),
+        };
+
+        if !self.choices.is_empty() && !self.choices.iter().any(|choice| choice == &value) {
+            let choices: Vec<String> = self.choices.iter().map(display_value).collect();
+            return Err(invalid(format!(
+                "'{}' is not one of: {}",
+                display_value(&value),
+                choices.join(", ")
+            )));
+        }
+
+        if let Some(regex) = self.regex()? {
+            let text = display_value(&value);
+            if !regex.is_match(&text) {
+                return Err(invalid(format!(
+                    "'{}' does not match pattern '{}'",
+                    text,
+                    self.pattern.as_deref().unwrap_or_default()
+                )));
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+/// Format a value the way a user would type it, without quotes around strings
+pub fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MANIFEST: &str = r#"
+description = "Test template"
+
+[[variables]]
+name = "python_version"
+default = "3.12"
+choices = ["3.11", "3.12"]
+
+[[variables]]
+name = "port"
+type = "integer"
+default = 8080
+
+[[variables]]
+name = "with_compose"
+type = "bool"
+default = false
+
+[[variables]]
+name = "team"
+pattern = "[a-z]+"
+"#;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        assert_eq!(manifest.description.as_deref(), Some("Test template"));
+        assert_eq!(manifest.variables.len(), 4);
+        assert_eq!(
+            manifest.variable("port").unwrap().kind,
+            VariableType::Integer
+        );
+        assert!(manifest.variable("missing").is_none());
+    }
+
+    #[test]
+    fn test_parse_invalid_manifest() {
+        assert!(matches!(
+            Manifest::parse("unknown = 1"),
+            Err(Error::InvalidTemplate(_))
+        ));
+
+        let bad_pattern = "[[variables]]\nname = \"x\"\npattern = \"[\"";
+        assert!(matches!(
+            Manifest::parse(bad_pattern),
+            Err(Error::InvalidTemplate(_))
+        ));
+
+        for bad_glob in [
+            "exclude = [\"[\"]",
+            "copy = [\"a/{b\"]",
+            "[include_if]\n\"[\" = \"x\"",
+            "[[modes]]\nglob = \"[\"\nmode = \"0755\"",
+            "[[merge]]\nglob = \"[\"\nstrategy = \"append\"",
+        ] {
+            assert!(matches!(
+                Manifest::parse(bad_glob),
+                Err(Error::InvalidTemplate(_))
+            ));
+        }
+
+        let bad_default = "[[variables]]\nname = \"x\"\ntype = \"integer\"\ndefault = \"abc\"";
+        assert!(matches!(
+            Manifest::parse(bad_default),
+            Err(Error::InvalidTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_defaults_and_values() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        let mut context = Context::new();
+        context.insert("team", "platform");
+        context.insert("port", "9000");
+
+        let context = manifest.resolve(context).unwrap();
+        assert_eq!(context.get("python_version"), Some(&json!("3.12")));
+        assert_eq!(context.get("port"), Some(&json!(9000)));
+        assert_eq!(context.get("with_compose"), Some(&json!(false)));
+        assert_eq!(context.get("team"), Some(&json!("platform")));
+    }
+
+    #[test]
+    fn test_resolve_rejects_missing_and_invalid_values() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+
+        let result = manifest.resolve(Context::new());
+        assert!(matches!(result, Err(Error::MissingVariable(name)) if name == "team"));
+
+        let mut context = Context::new();
+        context.insert("team", "Platform Team");
+        assert!(matches!(
+            manifest.resolve(context),
+            Err(Error::InvalidVariable(_))
+        ));
+
+        let mut context = Context::new();
+        context.insert("team", "platform");
+        context.insert("python_version", "2.7");
+        let err = manifest.resolve(context).unwrap_err();
+        assert!(err.to_string().contains("'2.7' is not one of: 3.11, 3.12"));
+    }
+
+    #[test]
+    fn test_file_rules() {
+        let manifest = Manifest::parse(
+            r#"
+exclude = ["**/*.orig", "notes"]
+
+[include_if]
+"docker-compose.yml" = "with_compose"
+"runtime-assets/" = "entrypoint != 'none'"
+"#,
+        )
+        .unwrap();
+
+        let includes = |path: &str, with_compose: bool, entrypoint: &str| {
+            let mut context = Context::new();
+            context.insert("with_compose", &with_compose);
+            context.insert("entrypoint", entrypoint);
+            manifest
+                .includes(Path::new(path), |condition| {
+                    tera::Tera::one_off(condition, &context, false)
+                })
+                .unwrap()
+        };
+
+        assert!(includes("Dockerfile", false, "none"));
+        assert!(!includes("Dockerfile.orig", true, "sh"));
+        assert!(!includes("src/app.py.orig", true, "sh"));
+        assert!(!includes("notes/todo.md", true, "sh"));
+        assert!(includes("docker-compose.yml", true, "none"));
+        assert!(!includes("docker-compose.yml", false, "none"));
+        assert!(includes(
+            "runtime-assets/usr/local/bin/entrypoint.sh",
+            false,
+            "sh"
+        ));
+        assert!(!includes(
+            "runtime-assets/usr/local/bin/entrypoint.sh",
+            false,
+            "none"
+        ));
+
+        // Undefined variables are false, but broken conditions are errors
+        assert!(!manifest
+            .includes(Path::new("docker-compose.yml"), |condition| {
+                tera::Tera::one_off(condition, &Context::new(), false)
+            })
+            .unwrap());
+        let broken =
+            Manifest::parse("[include_if]\n\"docker-compose.yml\" = \"with_compose ==\"\n")
+                .unwrap();
+        let err = broken
+            .includes(Path::new("docker-compose.yml"), |condition| {
+                tera::Tera::one_off(condition, &Context::new(), false)
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("docker-compose.yml"), "{}", err);
+
+        assert!(matches!(
+            Manifest::parse("exclude = [\"[\"]"),
+            Err(Error::InvalidTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn test_modes() {
+        let manifest = Manifest::parse(
+            r#"
+[[modes]]
+glob = "runtime-assets/usr/local/bin"
+mode = "0755"
+dir_mode = "0750"
+
+[[modes]]
+glob = "**/*.example"
+mode = "0600"
+
+[[modes]]
+glob = "runtime-assets/usr/local/bin/README.md"
+mode = "0o644"
+"#,
+        )
+        .unwrap();
+
+        let file_mode = |path: &str| manifest.file_mode(Path::new(path)).unwrap();
+        assert_eq!(
+            file_mode("runtime-assets/usr/local/bin/entrypoint"),
+            Some(0o755)
+        );
+        assert_eq!(
+            file_mode("runtime-assets/usr/local/bin/README.md"),
+            Some(0o644)
+        );
+        assert_eq!(file_mode("config/secrets.env.example"), Some(0o600));
+        assert_eq!(file_mode("Dockerfile"), None);
+        assert_eq!(
+            manifest
+                .dir_mode(Path::new("runtime-assets/usr/local/bin"))
+                .unwrap(),
+            Some(0o750)
+        );
+        assert_eq!(
+            manifest.dir_mode(Path::new("runtime-assets")).unwrap(),
+            None
+        );
+
+        for invalid in [
+            "[[modes]]\nglob = \"bin\"\nmode = \"755x\"\n",
+            "[[modes]]\nglob = \"bin\"\nmode = \"4755\"\n",
+            "[[modes]]\nglob = \"bin\"\n",
+        ] {
+            assert!(
+                matches!(Manifest::parse(invalid), Err(Error::InvalidTemplate(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_rules() {
+        let manifest = Manifest::parse(
+            r#"
+[[merge]]
+glob = "**/*.yml"
+strategy = "yaml"
+
+[[merge]]
+glob = "Makefile"
+strategy = "append"
+
+[[merge]]
+glob = ".github/workflows/release.yml"
+strategy = "replace"
+"#,
+        )
+        .unwrap();
+
+        let strategy = |path: &str| manifest.merge_strategy(Path::new(path)).unwrap();
+        assert_eq!(strategy("Makefile"), MergeStrategy::Append);
+        assert_eq!(strategy("compose/docker-compose.yml"), MergeStrategy::Yaml);
+        assert_eq!(
+            strategy(".github/workflows/release.yml"),
+            MergeStrategy::Replace
+        );
+        assert_eq!(strategy("Dockerfile"), MergeStrategy::Replace);
+
+        assert!(matches!(
+            Manifest::parse("[[merge]]\nglob = \"Makefile\"\nstrategy = \"concat\"\n"),
+            Err(Error::InvalidTemplate(_))
+        ));
+    }
+}
@@ -1,15 +1,83 @@
 use chrono::Utc;
-use include_dir::{include_dir, Dir, DirEntry};
-use serde::Serialize;
-use std::path::Path;
+use include_dir::{include_dir, Dir};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use tera::{Context, Tera};
-use tokio::fs;
 
-use crate::error::{Error, Result};
+use crate::error::{Diagnostic, Error, Result};
+
+pub mod archive;
+pub mod compose;
+pub mod filters;
+pub mod git;
+pub mod hooks;
+pub mod inspect;
+pub mod lint;
+pub mod manifest;
+pub mod output;
+pub mod pipeline;
+pub mod snapshot;
+pub mod source;
+pub mod staging;
+pub mod update;
+pub mod values;
+
+pub use compose::{Conflict, MergeStrategy};
+pub use git::GitReference;
+pub use hooks::{HookStage, Hooks};
+pub use inspect::{InspectedFile, InspectedVariable, Inspection};
+pub use lint::{Finding, Severity};
+pub use manifest::{Manifest, Variable, VariableType};
+pub use output::{write_output, FsOutput, MemoryFile, MemoryOutput, Output, TarOutput, ZipOutput};
+pub use pipeline::Plan;
+pub use snapshot::{CaseResult, CaseStatus, Mismatch, MismatchKind, TestCase};
+pub use source::{Template, TemplateFile, TemplateSource};
+pub use staging::Staging;
+pub use update::{Answers, UpdateStatus, ANSWERS_FILE};
+pub use values::Values;
 
 static TEMPLATES: Dir = include_dir!("templates");
 
-#[derive(Debug, Clone, Serialize)]
+/// Username used when none is given
+pub const DEFAULT_USERNAME: &str = "example";
+
+/// Vendor used when none is given
+pub const DEFAULT_VENDOR: &str = "Example Corp";
+
+/// Variables every template context provides, whatever the template declares
+pub const BUILTIN_VARIABLES: [&str; 7] = [
+    "repo_username",
+    "repo_namespace",
+    "image_name",
+    "vendor",
+    "version",
+    "build_date",
+    "vcs_ref",
+];
+
+/// Check that a name only contains alphanumeric characters, hyphens and underscores
+pub fn is_valid_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Check a username with the rules of [`is_valid_name`]. The prompt checks answers with it,
+/// and [`TemplateContext::validate`] checks the username a context is rendered with.
+pub fn validate_username(username: &str) -> Result<()> {
+    if is_valid_name(username) {
+        return Ok(());
+    }
+    Err(Error::InvalidVariable(format!(
+        "repo_username: only alphanumeric characters, hyphens and underscores are allowed, \
+         got '{}'",
+        username
+    )))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TemplateContext {
     pub repo_username: String,
     pub repo_namespace: String,
@@ -18,6 +86,10 @@ pub struct TemplateContext {
     pub version: String,
     pub build_date: String,
     pub vcs_ref: String,
+    /// Extra values, such as those from `--set` and `--values`, applied on top of the
+    /// built-in variables
+    #[serde(flatten)]
+    pub values: Values,
 }
 
 impl TemplateContext {
@@ -28,27 +100,60 @@ impl TemplateContext {
         }
 
         // Validate that namespace and project name only contain alphanumeric characters, hyphens, and underscores
-        let valid_chars = |s: &str| {
-            !s.is_empty()
-                && s.chars()
-                    .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
-        };
-
-        if !valid_chars(parts[0]) || !valid_chars(parts[1]) {
+        if !is_valid_name(parts[0]) || !is_valid_name(parts[1]) {
             return Err(Error::InvalidProjectName(project.to_string()));
         }
 
         Ok(Self {
-            repo_username: username.unwrap_or_else(|| "example".to_string()),
+            repo_username: username.unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
             repo_namespace: parts[0].to_string(),
             image_name: parts[1].to_string(),
-            vendor: vendor.unwrap_or_else(|| "Example Corp".to_string()),
+            vendor: vendor.unwrap_or_else(|| DEFAULT_VENDOR.to_string()),
             version: "0.1.0".to_string(),
             build_date: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
             vcs_ref: "HEAD".to_string(),
+            values: Values::new(),
         })
     }
 
+    /// Add extra values to the context, replacing any with the same name. Values for the
+    /// built-in variables replace the built-in ones, so that each name is recorded once.
+    pub fn with_values(mut self, values: impl IntoIterator<Item = (String, Value)>) -> Self {
+        for (key, value) in values {
+            let builtin = match key.as_str() {
+                "repo_username" => &mut self.repo_username,
+                "repo_namespace" => &mut self.repo_namespace,
+                "image_name" => &mut self.image_name,
+                "vendor" => &mut self.vendor,
+                "version" => &mut self.version,
+                "build_date" => &mut self.build_date,
+                "vcs_ref" => &mut self.vcs_ref,
+                _ => {
+                    self.values.insert(key, value);
+                    continue;
+                }
+            };
+            *builtin = match value {
+                Value::String(text) => text,
+                other => other.to_string(),
+            };
+        }
+        self
+    }
+
+    /// Check the built-in variables with the rules [`TemplateContext::new`] applies to the
+    /// project name, and [`validate_username`], whichever values have replaced them since.
+    /// Every context is checked before it is rendered.
+    pub fn validate(&self) -> Result<()> {
+        if !is_valid_name(&self.repo_namespace) || !is_valid_name(&self.image_name) {
+            return Err(Error::InvalidProjectName(format!(
+                "{}/{}",
+                self.repo_namespace, self.image_name
+            )));
+        }
+        validate_username(&self.repo_username)
+    }
+
     pub fn into_context(self) -> Context {
         let mut context = Context::new();
         context.insert("repo_username", &self.repo_username);
@@ -58,176 +163,660 @@ impl TemplateContext {
         context.insert("build_date", &self.build_date);
         context.insert("version", &self.version);
         context.insert("vcs_ref", &self.vcs_ref);
+        for (key, value) in &self.values {
+            context.insert(key, value);
+        }
         context
     }
 }
 
+/// A template available to the engine and where it comes from
+#[derive(Debug, Clone)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub source: TemplateSource,
+}
+
+/// Directory remote templates are cached in: `$ESSEX_CACHE_DIR`, or `essex` inside the
+/// user's cache directory
+pub fn default_cache_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("ESSEX_CACHE_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+        return PathBuf::from(dir).join("essex");
+    }
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".cache").join("essex"),
+        None => std::env::temp_dir().join("essex"),
+    }
+}
+
 pub struct TemplateEngine {
     tera: Tera,
+    templates_dir: Option<PathBuf>,
+    cache_dir: PathBuf,
+    parallelism: usize,
 }
 
 impl TemplateEngine {
+    /// Create an engine that only knows about the built-in templates, like
+    /// [`TemplateEngine::builtin`]. The argument is not read: use
+    /// [`TemplateEngine::from_templates_dir`] to add the templates of a directory.
     pub fn new<P: AsRef<Path>>(_templates_dir: P) -> Result<Self> {
-        // Create an empty Tera instance since we'll render strings directly
-        let tera = Tera::default();
-        Ok(Self { tera })
+        Ok(Self::builtin())
+    }
+
+    /// Create an engine that merges the templates found in `templates_dir` with the built-in
+    /// ones. Every subdirectory of `templates_dir` is a template, except hidden ones and
+    /// shared ones such as `_partials`, so point it at a directory that only holds templates.
+    /// Local templates override built-ins that share a name.
+    pub fn from_templates_dir<P: AsRef<Path>>(templates_dir: P) -> Result<Self> {
+        let templates_dir = templates_dir.as_ref();
+        if !templates_dir.is_dir() {
+            return Err(Error::InvalidPath(format!(
+                "Templates directory '{}' does not exist",
+                templates_dir.display()
+            )));
+        }
+
+        let mut shared = source::embedded_shared_files(&TEMPLATES)?;
+        shared.extend(source::local_shared_files(templates_dir)?);
+
+        Ok(Self {
+            tera: shared_registry(&shared)?,
+            templates_dir: Some(templates_dir.to_path_buf()),
+            cache_dir: default_cache_dir(),
+            parallelism: pipeline::default_parallelism(),
+        })
+    }
+
+    /// Create an engine that only knows about the built-in templates
+    pub fn builtin() -> Self {
+        let shared = source::embedded_shared_files(&TEMPLATES)
+            .and_then(|files| shared_registry(&files))
+            .expect("built-in shared templates are valid");
+
+        Self {
+            tera: shared,
+            templates_dir: None,
+            cache_dir: default_cache_dir(),
+            parallelism: pipeline::default_parallelism(),
+        }
+    }
+
+    /// Use `cache_dir` for templates fetched from git instead of the default
+    pub fn with_cache_dir<P: AsRef<Path>>(mut self, cache_dir: P) -> Self {
+        self.cache_dir = cache_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Render and write at most `parallelism` files at once
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    /// List available templates along with their source, sorted by name
+    pub fn templates(&self) -> Result<Vec<TemplateInfo>> {
+        let mut templates = BTreeMap::new();
+        for dir in TEMPLATES.dirs() {
+            let name = dir.path().to_string_lossy().into_owned();
+            if !source::is_shared_dir(&name) {
+                templates.insert(name, TemplateSource::Builtin);
+            }
+        }
+
+        if let Some(templates_dir) = &self.templates_dir {
+            for (name, path) in source::local_template_dirs(templates_dir)? {
+                templates.insert(name, TemplateSource::Local(path));
+            }
+        }
+
+        Ok(templates
+            .into_iter()
+            .map(|(name, source)| TemplateInfo { name, source })
+            .collect())
     }
 
     pub fn list_templates(&self) -> Result<Vec<String>> {
-        let mut templates = Vec::new();
-        for entry in TEMPLATES.entries() {
-            if entry.as_dir().is_some() {
-                templates.push(entry.path().to_string_lossy().into_owned());
+        Ok(self.templates()?.into_iter().map(|t| t.name).collect())
+    }
+
+    /// Load a template by name, preferring a local template over a built-in one.
+    ///
+    /// Git references such as `git+file:///srv/templates.git#python@v1.2` or
+    /// `gh:acme/templates//python@v1.2` are fetched into the cache directory first, and paths
+    /// to `.tar.gz` or `.zip` files are read as template archives.
+    pub fn load_template(&self, name: &str) -> Result<Template> {
+        let path = Path::new(name);
+        if archive::ArchiveFormat::from_path(path).is_some() && path.is_file() {
+            return archive::load(path);
+        }
+
+        if let Some(reference) = GitReference::parse(name) {
+            let checkout = reference.fetch(&self.cache_dir)?;
+            let mut template = Template::from_dir(name, &checkout.path)?;
+            template.source = TemplateSource::Git {
+                url: reference.url,
+                commit: checkout.commit,
+            };
+            return Ok(template);
+        }
+
+        let info = self
+            .templates()?
+            .into_iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| Error::TemplateNotFound(name.to_string()))?;
+
+        match &info.source {
+            TemplateSource::Builtin => {
+                let dir = TEMPLATES
+                    .get_dir(name)
+                    .ok_or_else(|| Error::TemplateNotFound(name.to_string()))?;
+                Template::from_embedded(name, dir)
+            }
+            TemplateSource::Local(path) => Template::from_dir(name, path),
+            TemplateSource::Archive(_) | TemplateSource::Git { .. } => {
+                Err(Error::TemplateNotFound(name.to_string()))
             }
         }
-        Ok(templates)
     }
 
-    /// Generate a project asynchronously
-    #[allow(dead_code)]
+    /// Generate a project asynchronously, returning the template files skipped by the
+    /// manifest's `exclude` and `include_if` rules.
+    ///
+    /// Files are rendered and written concurrently, into a staging directory so that a
+    /// failure leaves nothing behind.
     pub async fn generate_async(
         &self,
         template: &str,
         context: TemplateContext,
         output_dir: &Path,
-    ) -> Result<()> {
-        // Validate template exists
-        if !self.list_templates()?.contains(&template.to_string()) {
-            return Err(Error::TemplateNotFound(template.to_string()));
-        }
+    ) -> Result<Vec<PathBuf>> {
+        let rendered = self.render_async(template, context).await?;
 
-        // Create output directory
-        fs::create_dir_all(output_dir).await?;
+        let staging = Staging::new(output_dir)?;
+        pipeline::write(&rendered.files, staging.path(), self.parallelism).await?;
+        staging.commit()?;
+        set_dir_modes(&rendered.dir_modes, output_dir)?;
+        Ok(rendered.skipped)
+    }
 
-        // Convert context to Tera context
-        let context = context.into_context();
+    /// Generate a project, returning the template files skipped by the manifest's `exclude`
+    /// and `include_if` rules
+    pub fn generate(
+        &self,
+        template: &str,
+        context: TemplateContext,
+        output_dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        pipeline::block_on(self.generate_async(template, context, output_dir))
+    }
 
-        // Find the template directory
-        let template_dir = TEMPLATES
-            .get_dir(template)
-            .ok_or_else(|| Error::TemplateNotFound(template.to_string()))?;
+    /// Generate a project into `output`, such as a [`MemoryOutput`] or a [`TarOutput`]
+    /// stream, returning the template files skipped by the manifest's `exclude` and
+    /// `include_if` rules
+    pub async fn generate_to_async<O: Output + ?Sized>(
+        &self,
+        template: &str,
+        context: TemplateContext,
+        output: &mut O,
+    ) -> Result<Vec<PathBuf>> {
+        let rendered = self.render_async(template, context).await?;
+        write_output(&rendered, output)?;
+        Ok(rendered.skipped)
+    }
 
-        // Process template files recursively
-        self.process_directory_async(template_dir, output_dir, &context)
-            .await?;
+    /// Generate a project into `output`, see [`TemplateEngine::generate_to_async`]
+    pub fn generate_to<O: Output + ?Sized>(
+        &self,
+        template: &str,
+        context: TemplateContext,
+        output: &mut O,
+    ) -> Result<Vec<PathBuf>> {
+        let rendered = self.render(template, context)?;
+        write_output(&rendered, output)?;
+        Ok(rendered.skipped)
+    }
 
-        Ok(())
+    /// Render every file of a template in memory without touching the disk
+    ///
+    /// `template` may layer several templates, as in `basic+compose`.
+    pub async fn render_async(&self, template: &str, context: TemplateContext) -> Result<Rendered> {
+        // Validate templates exist
+        let layers = self.load_layers(&compose::layer_names(template, &[]))?;
+        self.render_layers_async(&layers, context).await
     }
 
-    #[allow(dead_code)]
-    #[allow(clippy::only_used_in_recursion)]
-    async fn process_directory_async<'a>(
-        &'a self,
-        dir: &'a Dir<'_>,
-        output_dir: &'a Path,
-        context: &'a Context,
-    ) -> std::result::Result<(), Error> {
-        for entry in dir.entries() {
-            match entry {
-                DirEntry::Dir(subdir) => {
-                    let new_output_dir = output_dir.join(subdir.path().strip_prefix(dir.path())?);
-                    fs::create_dir_all(&new_output_dir).await?;
-                    Box::pin(self.process_directory_async(subdir, &new_output_dir, context))
-                        .await?;
-                }
-                DirEntry::File(file) => {
-                    let output_path = output_dir.join(file.path().strip_prefix(dir.path())?);
-                    let content = file
-                        .contents_utf8()
-                        .ok_or_else(|| {
-                            Error::InvalidTemplate("Template file is not valid UTF-8".to_string())
-                        })?
-                        .to_string();
-                    let rendered = tera::Tera::one_off(&content, context, false)?;
-                    fs::write(&output_path, rendered).await?;
-
-                    // Set executable permissions for .sh files
-                    if output_path.extension().is_some_and(|ext| ext == "sh") {
-                        #[cfg(unix)]
-                        {
-                            use std::os::unix::fs::PermissionsExt;
-                            let mut perms = fs::metadata(&output_path).await?.permissions();
-                            perms.set_mode(0o755);
-                            fs::set_permissions(&output_path, perms).await?;
-                        }
+    /// Render every file of a template in memory without touching the disk
+    ///
+    /// `template` may layer several templates, as in `basic+compose`.
+    pub fn render(&self, template: &str, context: TemplateContext) -> Result<Rendered> {
+        pipeline::block_on(self.render_async(template, context))
+    }
+
+    /// Load the layers of a composed template, in order
+    pub fn load_layers(&self, names: &[String]) -> Result<Vec<Template>> {
+        if names.is_empty() {
+            return Err(Error::TemplateNotFound(String::new()));
+        }
+        names.iter().map(|name| self.load_template(name)).collect()
+    }
+
+    /// Render several templates on top of each other into one project.
+    ///
+    /// When two layers produce the same file, the later layer's `merge` rules decide how the
+    /// files are combined (it replaces the earlier file by default), and the file is listed
+    /// in [`Rendered::conflicts`].
+    pub async fn render_layers_async(
+        &self,
+        layers: &[Template],
+        context: TemplateContext,
+    ) -> Result<Rendered> {
+        let mut composed = Rendered::default();
+        let mut producers: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        let mut strategies = BTreeMap::new();
+        let mut positions: HashMap<PathBuf, usize> = HashMap::new();
+
+        for layer in layers {
+            let rendered = Plan::new(&self.tera, layer, context.clone())?
+                .render(self.parallelism)
+                .await?;
+            for file in rendered.files {
+                let layer_names = producers.entry(file.path.clone()).or_default();
+                layer_names.push(layer.name.clone());
+
+                match positions.get(&file.path) {
+                    Some(&index) => {
+                        let existing = &mut composed.files[index];
+                        let strategy = layer.manifest.merge_strategy(&file.path)?;
+                        existing.contents = compose::merge(
+                            strategy,
+                            &file.path,
+                            &existing.contents,
+                            &file.contents,
+                        )?;
+                        existing.mode = file.mode;
+                        strategies.insert(file.path, strategy);
+                    }
+                    None => {
+                        positions.insert(file.path.clone(), composed.files.len());
+                        composed.files.push(file);
                     }
                 }
             }
+            composed.skipped.extend(rendered.skipped);
+            composed.copied.extend(rendered.copied);
+            composed.dir_modes.extend(rendered.dir_modes);
         }
 
-        Ok(())
+        composed.conflicts = strategies
+            .into_iter()
+            .map(|(path, strategy)| Conflict {
+                layers: producers.remove(&path).unwrap_or_default(),
+                path,
+                strategy,
+            })
+            .collect();
+        Ok(composed)
     }
 
-    pub fn generate(
-        &mut self,
-        template: &str,
+    /// Render several templates on top of each other into one project, see
+    /// [`TemplateEngine::render_layers_async`]
+    pub fn render_layers(&self, layers: &[Template], context: TemplateContext) -> Result<Rendered> {
+        pipeline::block_on(self.render_layers_async(layers, context))
+    }
+
+    /// Render each of a template's test cases and compare the result to its expected files
+    pub fn test(&self, template: &Template) -> Result<Vec<CaseResult>> {
+        let mut results = Vec::new();
+        for case in snapshot::test_cases(template)? {
+            let rendered = case
+                .context()
+                .and_then(|context| self.render_template(template, context));
+            results.push(CaseResult::new(&case, rendered));
+        }
+        Ok(results)
+    }
+
+    /// Describe the variables a template uses and the files it produces, without rendering it
+    pub fn inspect(&self, template: &Template) -> Result<Inspection> {
+        inspect::inspect(&self.tera, template)
+    }
+
+    /// Check a template for problems that would break or spoil generation
+    pub fn lint(&self, template: &Template) -> Result<Vec<Finding>> {
+        lint::lint(&self.tera, template)
+    }
+
+    /// Render an already loaded template in memory without touching the disk
+    pub fn render_template(
+        &self,
+        template: &Template,
         context: TemplateContext,
-        output_dir: &Path,
-    ) -> Result<()> {
-        // Validate template exists
-        if !self.list_templates()?.contains(&template.to_string()) {
-            return Err(Error::TemplateNotFound(template.to_string()));
+    ) -> Result<Rendered> {
+        let plan = Plan::new(&self.tera, template, context)?;
+        pipeline::block_on(plan.render(self.parallelism))
+    }
+}
+
+/// The files rendered from a template
+#[derive(Debug, Clone, Default)]
+pub struct Rendered {
+    pub files: Vec<RenderedFile>,
+    /// Template files left out by the manifest's `exclude` and `include_if` rules
+    pub skipped: Vec<PathBuf>,
+    /// Rendered paths of the files copied without rendering, such as binary files and those
+    /// matching the manifest's `copy` globs
+    pub copied: Vec<PathBuf>,
+    /// Modes the manifest declares for generated directories, by rendered path
+    pub dir_modes: BTreeMap<PathBuf, u32>,
+    /// Files produced by more than one layer of a composed template
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A file rendered from a template, not yet written to disk
+#[derive(Debug, Clone)]
+pub struct RenderedFile {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+    pub mode: u32,
+}
+
+/// Mode for a generated file: the manifest's `modes` rules win, otherwise the source file's
+/// mode is kept (0644 if it has none). `.sh` files are always executable by whoever can
+/// read them.
+fn file_mode(manifest: &Manifest, file: &TemplateFile, path: &Path) -> Result<u32> {
+    if let Some(mode) = manifest.file_mode(&file.path)? {
+        return Ok(mode);
+    }
+
+    let mode = file.mode.unwrap_or(0o644);
+    if path.extension().is_some_and(|ext| ext == "sh") {
+        Ok(mode | ((mode & 0o444) >> 2))
+    } else {
+        Ok(mode)
+    }
+}
+
+/// Record the modes the manifest declares for the directories above a template file
+fn collect_dir_modes(
+    manifest: &Manifest,
+    file: &TemplateFile,
+    mut render: impl FnMut(&str) -> tera::Result<String>,
+    dir_modes: &mut BTreeMap<PathBuf, u32>,
+) -> Result<()> {
+    for dir in file.path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() {
+            break;
+        }
+        if let Some(mode) = manifest.dir_mode(dir)? {
+            dir_modes.insert(render_path(dir, &mut render)?, mode);
         }
+    }
+    Ok(())
+}
 
-        // Create output directory
-        std::fs::create_dir_all(output_dir)?;
+/// Apply directory modes below `root`, deepest directories first
+pub fn set_dir_modes(dir_modes: &BTreeMap<PathBuf, u32>, root: &Path) -> Result<()> {
+    for (dir, mode) in dir_modes.iter().rev() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(root.join(dir), std::fs::Permissions::from_mode(*mode))?;
+        }
+    }
 
-        // Convert context to Tera context
-        let context = context.into_context();
+    Ok(())
+}
 
-        // Find the template directory
-        let template_dir = TEMPLATES
-            .get_dir(template)
-            .ok_or_else(|| Error::TemplateNotFound(template.to_string()))?;
+/// Write rendered files as a new project, through a staging directory so that a failure
+/// leaves nothing behind
+pub fn write_project(files: &[RenderedFile], output_dir: &Path) -> Result<()> {
+    let staging = Staging::new(output_dir)?;
+    write_files(files, staging.path())?;
+    staging.commit()
+}
 
-        // Copy and process template files
-        self.copy_template_files(template_dir, output_dir, &context)?;
+/// Write rendered files directly into `to`, replacing files with the same path
+pub fn write_files(files: &[RenderedFile], to: &Path) -> Result<()> {
+    pipeline::block_on(pipeline::write(files, to, pipeline::default_parallelism()))
+}
 
-        Ok(())
+/// Build the registry of shared templates that template files can `include`, `extend` and
+/// `import` by path, such as `_partials/labels.dockerfile`
+fn shared_registry(files: &[TemplateFile]) -> Result<Tera> {
+    let mut tera = Tera::default();
+    // Templates are registered under their file names, which must not switch on HTML escaping
+    tera.autoescape_on(Vec::new());
+    filters::register(&mut tera);
+
+    let templates: Vec<(String, &str)> = files
+        .iter()
+        .filter(|file| !file.is_binary())
+        .filter_map(|file| {
+            let content = std::str::from_utf8(&file.contents).ok()?;
+            Some((template_name(&file.path), content))
+        })
+        .collect();
+    tera.add_raw_templates(
+        templates
+            .iter()
+            .map(|(name, content)| (name.as_str(), *content)),
+    )
+    .map_err(|e| {
+        let sources = files.iter().filter_map(|file| {
+            Some((
+                file.path.as_path(),
+                std::str::from_utf8(&file.contents).ok()?,
+            ))
+        });
+        registration_error(sources, e)
+    })?;
+    Ok(tera)
+}
+
+/// Name a file is registered under in Tera: its path with `/` separators
+fn template_name(path: &Path) -> String {
+    path.iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The text of a template file to render, or `None` for binary files and files the
+/// manifest says to copy as they are
+fn template_text<'a>(manifest: &Manifest, file: &'a TemplateFile) -> Result<Option<&'a str>> {
+    if file.is_binary() || manifest.copies(&file.path)? {
+        return Ok(None);
     }
+    Ok(std::str::from_utf8(&file.contents).ok())
+}
 
-    fn copy_template_files(&mut self, dir: &Dir, to: &Path, context: &Context) -> Result<()> {
-        // Process all entries in the directory
-        for entry in dir.entries() {
-            match entry {
-                DirEntry::Dir(subdir) => {
-                    // Create the subdirectory
-                    let rel_path = subdir.path().strip_prefix(dir.path())?;
-                    let output_subdir = to.join(rel_path);
-                    std::fs::create_dir_all(&output_subdir)?;
+/// Render the file and directory names of a template path, such as
+/// `src/{{ image_name }}/main.py`.
+///
+/// Each segment is rendered on its own. The rendered path must stay inside the output
+/// directory: empty segments, absolute paths and `..` are rejected.
+fn render_path(
+    path: &Path,
+    mut render: impl FnMut(&str) -> tera::Result<String>,
+) -> Result<PathBuf> {
+    let mut rendered = PathBuf::new();
+    for segment in path.iter() {
+        let segment = segment.to_string_lossy();
+        if !segment.contains('{') {
+            rendered.push(segment.as_ref());
+            continue;
+        }
 
-                    // Recursively process the subdirectory
-                    self.copy_template_files(subdir, &output_subdir, context)?;
-                }
-                DirEntry::File(file) => {
-                    let rel_path = file.path().strip_prefix(dir.path())?;
-                    let output_path = to.join(rel_path);
+        let name = render(&segment).map_err(|e| render_error(path, None, e))?;
+        if name.trim().is_empty() {
+            return Err(Error::InvalidPath(format!(
+                "{}: '{}' renders to an empty name",
+                path.display(),
+                segment
+            )));
+        }
+        rendered.push(name);
+    }
 
-                    if let Some(parent) = output_path.parent() {
-                        std::fs::create_dir_all(parent)?;
-                    }
+    if !stays_inside(&rendered) {
+        return Err(Error::InvalidPath(format!(
+            "{}: renders to '{}', which is outside the output directory",
+            path.display(),
+            rendered.display()
+        )));
+    }
 
-                    let content = file.contents_utf8().ok_or_else(|| {
-                        Error::InvalidTemplate("Template file is not valid UTF-8".to_string())
-                    })?;
-                    let rendered = self.tera.render_str(content, context)?;
-                    std::fs::write(&output_path, rendered)?;
-
-                    // Set executable permissions for .sh files
-                    if output_path.extension().is_some_and(|ext| ext == "sh") {
-                        #[cfg(unix)]
-                        {
-                            use std::os::unix::fs::PermissionsExt;
-                            let mut perms = std::fs::metadata(&output_path)?.permissions();
-                            perms.set_mode(0o755);
-                            std::fs::set_permissions(&output_path, perms)?;
-                        }
-                    }
-                }
+    Ok(rendered)
+}
+
+/// Whether a relative path stays inside the directory it is joined to: it has no root and
+/// only normal components, so no `..`
+fn stays_inside(path: &Path) -> bool {
+    !path.has_root()
+        && path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+fn duplicate_path_error(path: &Path, rendered: &Path) -> Error {
+    Error::InvalidPath(format!(
+        "{}: renders to '{}', which another template file already produces",
+        path.display(),
+        rendered.display()
+    ))
+}
+
+/// Turn a Tera error into a diagnostic for the template file at `path`. When `source`, the
+/// text Tera was given, is known, the diagnostic points at the line and column of the error.
+fn render_error(path: &Path, source: Option<&str>, error: tera::Error) -> Error {
+    Error::Diagnostic(Box::new(render_diagnostic(path, source, error)))
+}
+
+/// The diagnostic [`render_error`] reports
+fn render_diagnostic(path: &Path, source: Option<&str>, error: tera::Error) -> Diagnostic {
+    let mut message = error.to_string();
+    let mut position = None;
+    let mut cause = std::error::Error::source(&error);
+    while let Some(e) = cause {
+        let text = e.to_string();
+        match parse_error_position(&text) {
+            Some((line, column, expected)) => {
+                position = Some((line, column));
+                message.push_str(&format!(": {}", expected));
             }
+            None => message.push_str(&format!(": {}", text)),
         }
+        cause = e.source();
+    }
 
-        Ok(())
+    let position = source.and_then(|source| {
+        position.or_else(|| failing_name_position(source, &message, &template_name(path)))
+    });
+    let snippet = source
+        .zip(position)
+        .and_then(|(source, (line, _))| source.lines().nth(line - 1))
+        .map(str::to_string);
+
+    Diagnostic {
+        template: None,
+        file: path.to_path_buf(),
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        message,
+        snippet,
+    }
+}
+
+/// Line, column and message of a Tera syntax error, which reads like
+///
+/// ```text
+///  --> 2:17
+///   |
+/// 2 | FROM {{ image | }}
+///   |                 ^---
+///   |
+///   = expected an identifier (must start with a-z)
+/// ```
+fn parse_error_position(text: &str) -> Option<(usize, usize, String)> {
+    let position = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("--> "))?;
+    let (line, column) = position.split_once(':')?;
+    let expected = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("= "))
+        .unwrap_or("syntax error");
+    Some((
+        line.parse().ok()?,
+        column.parse().ok()?,
+        expected.to_string(),
+    ))
+}
+
+/// Tera does not say where rendering failed, but names what it failed on, as in
+/// ``Variable `port` not found in context while rendering 'Dockerfile'``. Find the first
+/// use of that name inside a tag of `source`, unless the failure was in another template.
+fn failing_name_position(source: &str, message: &str, name: &str) -> Option<(usize, usize)> {
+    if let Some((_, rendering)) = message.rsplit_once("while rendering '") {
+        if rendering.trim_end_matches('\'') != name {
+            return None;
+        }
+    }
+    let (_, rest) = message.split_once('`')?;
+    let (failing, _) = rest.split_once('`')?;
+    name_position(source, failing)
+}
+
+/// Line and column of the first use of `name` inside a tag of `source`
+fn name_position(source: &str, name: &str) -> Option<(usize, usize)> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    source.lines().enumerate().find_map(|(index, line)| {
+        if !line.contains("{{") && !line.contains("{%") {
+            return None;
+        }
+        line.match_indices(name).find_map(|(start, _)| {
+            let end = start + name.len();
+            let before = line[..start].chars().next_back();
+            let after = line[end..].chars().next();
+            if before.is_some_and(is_word) || after.is_some_and(is_word) {
+                return None;
+            }
+            Some((index + 1, line[..start].chars().count() + 1))
+        })
+    })
+}
+
+/// Tera error text with its causes, for failures that are not in a single file
+fn error_chain(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Diagnose a failure to register templates, pointing at the file Tera failed to parse
+fn registration_error<'a>(
+    files: impl IntoIterator<Item = (&'a Path, &'a str)>,
+    error: tera::Error,
+) -> Error {
+    let failed = files.into_iter().find(|(path, _)| {
+        error.to_string() == format!("Failed to parse '{}'", template_name(path))
+    });
+    match failed {
+        Some((path, source)) => render_error(path, Some(source), error),
+        None => Error::TemplateError(error_chain(&error)),
     }
 }
 
@@ -239,7 +828,7 @@ mod tests {
     #[test]
     fn test_template_engine() -> Result<()> {
         let temp_dir = tempdir()?;
-        let mut engine = TemplateEngine::new(".")?;
+        let engine = TemplateEngine::new(".")?;
         let context = TemplateContext::new(
             "test/project",
             Some("testuser".to_string()),
@@ -259,10 +848,602 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_generate_to_memory_and_archive() -> Result<()> {
+        let engine = TemplateEngine::builtin();
+        let context = TemplateContext::new("acme/app", None, None)?;
+
+        let mut memory = MemoryOutput::new();
+        engine
+            .generate_to_async("basic", context.clone(), &mut memory)
+            .await?;
+        let entrypoint = &memory.files[Path::new("runtime-assets/usr/local/bin/entrypoint.sh")];
+        assert_eq!(entrypoint.mode & 0o111, 0o111);
+        assert!(memory.files.contains_key(Path::new("Dockerfile")));
+
+        let mut archive = TarOutput::gzip(Vec::new());
+        engine.generate_to("basic", context, &mut archive)?;
+        let bytes = archive.into_inner()?;
+        let mut entries = tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_slice()));
+        let mut paths: Vec<PathBuf> = entries
+            .entries()?
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, memory.files.keys().cloned().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_values_override_defaults() -> Result<()> {
+        let context = TemplateContext::new("test/project", None, None)?.with_values([
+            ("version".to_string(), Value::from("2.0.0")),
+            ("author_email".to_string(), Value::from("ops@acme.io")),
+        ]);
+        let tera_ctx = context.into_context();
+        assert_eq!(tera_ctx.get("version"), Some(&Value::from("2.0.0")));
+        assert_eq!(
+            tera_ctx.get("author_email"),
+            Some(&Value::from("ops@acme.io"))
+        );
+        assert_eq!(tera_ctx.get("image_name"), Some(&Value::from("project")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_rejects_invalid_overrides() -> Result<()> {
+        let context = TemplateContext::new("acme/app", None, None)?;
+        assert!(context.validate().is_ok());
+        for (key, value) in [
+            ("image_name", "../../escaped"),
+            ("repo_namespace", "/tmp"),
+            ("image_name", ""),
+        ] {
+            let context = context
+                .clone()
+                .with_values([(key.to_string(), Value::from(value))]);
+            assert!(matches!(
+                context.validate(),
+                Err(Error::InvalidProjectName(_))
+            ));
+        }
+
+        let engine = TemplateEngine::builtin();
+        let context = context.with_values([("image_name".to_string(), Value::from("../app"))]);
+        assert!(matches!(
+            engine.render("basic", context),
+            Err(Error::InvalidProjectName(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_rejects_invalid_username() -> Result<()> {
+        let context = TemplateContext::new("test/project", Some("ci-bot_2".to_string()), None)?;
+        assert!(context.validate().is_ok());
+
+        let engine = TemplateEngine::builtin();
+        let given = TemplateContext::new("test/project", Some("John Doe".to_string()), None)?;
+        let overridden =
+            context.with_values([("repo_username".to_string(), Value::from("John Doe"))]);
+        for context in [given, overridden] {
+            assert!(matches!(context.validate(), Err(Error::InvalidVariable(_))));
+            assert!(matches!(
+                engine.render("basic", context),
+                Err(Error::InvalidVariable(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_templates_override_builtin() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let basic_dir = templates_dir.path().join("basic");
+        std::fs::create_dir_all(&basic_dir)?;
+        std::fs::write(
+            basic_dir.join("Dockerfile"),
+            "FROM scratch # {{ image_name }}",
+        )?;
+        std::fs::create_dir_all(basic_dir.join(".git"))?;
+        std::fs::write(basic_dir.join(".git/HEAD"), "ref: refs/heads/main\n")?;
+        std::fs::create_dir_all(basic_dir.join(".github"))?;
+        std::fs::write(basic_dir.join(".github/ci.yml"), "name: {{ image_name }}\n")?;
+        std::fs::create_dir_all(templates_dir.path().join("house"))?;
+        std::fs::create_dir_all(templates_dir.path().join(".git"))?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let templates = engine.templates()?;
+        let names: Vec<&str> = templates.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["basic", "house"]);
+        assert_eq!(
+            templates[0].source,
+            TemplateSource::Local(basic_dir.clone())
+        );
+
+        let output_dir = tempdir()?;
+        let context = TemplateContext::new("test/project", None, None)?;
+        engine.generate("basic", context, output_dir.path())?;
+
+        let dockerfile = std::fs::read_to_string(output_dir.path().join("Dockerfile"))?;
+        assert_eq!(dockerfile, "FROM scratch # project");
+        assert!(!output_dir.path().join("Makefile").exists());
+        assert!(output_dir.path().join(".github/ci.yml").exists());
+        assert!(!output_dir.path().join(".git").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_variables() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        std::fs::create_dir_all(&service_dir)?;
+        std::fs::write(
+            service_dir.join("essex.toml"),
+            "[[variables]]\nname = \"port\"\ntype = \"integer\"\ndefault = 8080\n\n\
+             [[variables]]\nname = \"vendor\"\npattern = \"[A-Z][a-z]+\"\n",
+        )?;
+        std::fs::write(service_dir.join("config"), "{{ vendor }}:{{ port + 1 }}")?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+
+        let output_dir = tempdir()?;
+        let context = TemplateContext::new("test/project", None, Some("Acme".to_string()))?;
+        engine.generate("service", context, output_dir.path())?;
+        let config = std::fs::read_to_string(output_dir.path().join("config"))?;
+        assert_eq!(config, "Acme:8081");
+        assert!(!output_dir.path().join("essex.toml").exists());
+
+        // Invalid values are rejected before anything is written
+        let output_dir = tempdir()?;
+        let project_dir = output_dir.path().join("project");
+        let context = TemplateContext::new("test/project", None, Some("acme corp".to_string()))?;
+        let result = engine.generate("service", context, &project_dir);
+        assert!(matches!(result, Err(Error::InvalidVariable(_))));
+        assert!(!project_dir.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_errors_point_at_the_failing_line() -> Result<()> {
+        let engine = TemplateEngine::builtin();
+        let render = |path: &str, contents: &str| {
+            let file = TemplateFile {
+                path: PathBuf::from(path),
+                contents: contents.as_bytes().to_vec(),
+                mode: None,
+            };
+            let template = Template::new("svc", TemplateSource::Builtin, vec![file])?;
+            let context = TemplateContext::new("acme/app", None, None)?;
+            engine.render_template(&template, context)
+        };
+
+        let Err(Error::Diagnostic(syntax)) =
+            render("Dockerfile", "FROM scratch\nLABEL a={{ a | }}\n")
+        else {
+            panic!("expected a diagnostic");
+        };
+        assert_eq!(syntax.template.as_deref(), Some("svc"));
+        assert_eq!(syntax.file, PathBuf::from("Dockerfile"));
+        assert_eq!((syntax.line, syntax.column), (Some(2), Some(16)));
+        assert_eq!(syntax.snippet.as_deref(), Some("LABEL a={{ a | }}"));
+        assert!(syntax
+            .message
+            .ends_with("expected an identifier (must start with a-z)"));
+
+        let Err(Error::Diagnostic(missing)) = render(
+            "Makefile",
+            "# {{ image_name }}\n\ntag:\n\t@echo {{ missing_tag }}\n",
+        ) else {
+            panic!("expected a diagnostic");
+        };
+        assert_eq!((missing.line, missing.column), (Some(4), Some(11)));
+        assert!(missing.message.contains("Variable `missing_tag` not found"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_partials_and_base_templates() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let root = templates_dir.path();
+        std::fs::create_dir_all(root.join("_partials"))?;
+        std::fs::create_dir_all(root.join("_base"))?;
+        std::fs::create_dir_all(root.join("svc"))?;
+        std::fs::write(
+            root.join("_partials/labels.dockerfile"),
+            "LABEL vendor=\"{{ vendor }}\"",
+        )?;
+        std::fs::write(
+            root.join("_partials/macros.txt"),
+            "{% macro target(name) %}{{ name }}:\n\t@echo {{ name }}{% endmacro target %}",
+        )?;
+        std::fs::write(
+            root.join("_base/Dockerfile"),
+            "FROM {% block from %}scratch{% endblock from %}\n\
+             {% include \"_partials/labels.dockerfile\" %}\n",
+        )?;
+        std::fs::write(
+            root.join("svc/Dockerfile"),
+            "{% extends \"_base/Dockerfile\" %}{% block from %}python:3.12{% endblock from %}",
+        )?;
+        std::fs::write(
+            root.join("svc/Makefile"),
+            "{% import \"_partials/macros.txt\" as macros %}{{ macros::target(name=\"build\") }}",
+        )?;
+        std::fs::write(root.join("svc/index.html"), "<p>{{ vendor }}</p>")?;
+
+        let engine = TemplateEngine::from_templates_dir(root)?;
+        let names: Vec<_> = engine.templates()?.into_iter().map(|t| t.name).collect();
+        assert!(names.contains(&"svc".to_string()));
+        assert!(!names.iter().any(|name| name.starts_with('_')));
+
+        let context = TemplateContext::new("acme/app", None, Some("R&D".to_string()))?;
+        let files = engine.render("svc", context.clone())?.files;
+        let contents: BTreeMap<_, _> = files
+            .iter()
+            .map(|f| {
+                (
+                    f.path.clone(),
+                    String::from_utf8_lossy(&f.contents).into_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            contents[Path::new("Dockerfile")],
+            "FROM python:3.12\nLABEL vendor=\"R&D\"\n"
+        );
+        assert_eq!(contents[Path::new("Makefile")], "build:\n\t@echo build");
+        assert_eq!(contents[Path::new("index.html")], "<p>R&D</p>");
+
+        let output_dir = tempdir()?;
+        engine
+            .generate_async("svc", context, output_dir.path())
+            .await?;
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join("Dockerfile"))?,
+            contents[Path::new("Dockerfile")]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_filters_in_sync_and_async_rendering() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        std::fs::create_dir_all(service_dir.join("{{ image_name | dns_label }}"))?;
+        std::fs::write(
+            service_dir.join("{{ image_name | dns_label }}/env"),
+            "{{ image_name | screaming_snake }}_TAG={{ version | semver_bump(part='minor') }}",
+        )?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let context = TemplateContext::new("acme/My_App", None, None)?;
+        let files = engine.render("service", context.clone())?.files;
+        assert_eq!(files[0].path, PathBuf::from("my-app/env"));
+        assert_eq!(files[0].contents, b"MY_APP_TAG=0.2.0");
+
+        let output_dir = tempdir()?;
+        engine
+            .generate_async("service", context, output_dir.path())
+            .await?;
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join("my-app/env"))?,
+            "MY_APP_TAG=0.2.0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_composed_templates() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let root = templates_dir.path();
+        std::fs::create_dir_all(root.join("base"))?;
+        std::fs::create_dir_all(root.join("extra"))?;
+        std::fs::write(
+            root.join("base/Makefile"),
+            "build:\n\tmake {{ image_name }}",
+        )?;
+        std::fs::write(root.join("base/README.md"), "base")?;
+        std::fs::write(
+            root.join("extra/essex.toml"),
+            "[[merge]]\nglob = \"Makefile\"\nstrategy = \"append\"\n",
+        )?;
+        std::fs::write(root.join("extra/Makefile"), "test:\n\tmake check\n")?;
+        std::fs::write(root.join("extra/README.md"), "extra")?;
+        std::fs::write(root.join("extra/ci.yml"), "on: push")?;
+
+        let engine = TemplateEngine::from_templates_dir(root)?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+        let rendered = engine.render("base+extra", context)?;
+
+        let paths: Vec<_> = rendered.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("Makefile"),
+                PathBuf::from("README.md"),
+                PathBuf::from("ci.yml")
+            ]
+        );
+        assert_eq!(
+            rendered.files[0].contents,
+            b"build:\n\tmake app\n\ntest:\n\tmake check\n"
+        );
+        assert_eq!(rendered.files[1].contents, b"extra");
+        assert_eq!(
+            rendered.conflicts,
+            vec![
+                Conflict {
+                    path: PathBuf::from("Makefile"),
+                    layers: vec!["base".to_string(), "extra".to_string()],
+                    strategy: MergeStrategy::Append,
+                },
+                Conflict {
+                    path: PathBuf::from("README.md"),
+                    layers: vec!["base".to_string(), "extra".to_string()],
+                    strategy: MergeStrategy::Replace,
+                },
+            ]
+        );
+
+        assert!(matches!(
+            engine.render(
+                "base+missing",
+                TemplateContext::new("acme/app", None, None)?
+            ),
+            Err(Error::TemplateNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_generation_leaves_nothing_behind() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let broken_dir = templates_dir.path().join("broken");
+        std::fs::create_dir_all(broken_dir.join("src"))?;
+        std::fs::write(broken_dir.join("README.md"), "# {{ image_name }}")?;
+        std::fs::write(broken_dir.join("src/app.conf"), "{% if %}")?;
+        std::fs::write(broken_dir.join("zz.txt"), "never rendered")?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let output_dir = tempdir()?;
+        let project_dir = output_dir.path().join("project");
+
+        let context = TemplateContext::new("test/project", None, None)?;
+        let err = engine
+            .generate("broken", context.clone(), &project_dir)
+            .unwrap_err();
+        assert!(err.to_string().contains("src/app.conf"), "{}", err);
+        assert!(!project_dir.exists());
+        assert_eq!(std::fs::read_dir(output_dir.path())?.count(), 0);
+
+        let err = engine
+            .generate_async("broken", context, &project_dir)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("src/app.conf"), "{}", err);
+        assert!(!project_dir.exists());
+        assert_eq!(std::fs::read_dir(output_dir.path())?.count(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_templated_paths() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        std::fs::create_dir_all(service_dir.join("src/{{ image_name }}"))?;
+        std::fs::create_dir_all(service_dir.join("charts/{{ image_name }}"))?;
+        std::fs::write(
+            service_dir.join("src/{{ image_name }}/main.py"),
+            "# {{ image_name }}",
+        )?;
+        std::fs::write(
+            service_dir.join("charts/{{ image_name }}/{{ repo_namespace }}.sh"),
+            "#!/bin/sh",
+        )?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+        let files = engine.render("service", context.clone())?.files;
+        let paths: Vec<_> = files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("charts/app/acme.sh"),
+                PathBuf::from("src/app/main.py")
+            ]
+        );
+        assert_eq!(files[0].mode, 0o755);
+
+        let output_dir = tempdir()?;
+        engine
+            .generate_async("service", context, output_dir.path())
+            .await?;
+        assert!(output_dir.path().join("src/app/main.py").is_file());
+        assert!(output_dir.path().join("charts/app/acme.sh").is_file());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conditional_files() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        std::fs::create_dir_all(service_dir.join("runtime-assets"))?;
+        std::fs::write(
+            service_dir.join("essex.toml"),
+            "exclude = [\"*.orig\"]\n\n[include_if]\n\"docker-compose.yml\" = \"with_compose\"\n\n\
+             [[variables]]\nname = \"with_compose\"\ntype = \"bool\"\ndefault = false\n",
+        )?;
+        std::fs::write(service_dir.join("Dockerfile"), "FROM scratch")?;
+        std::fs::write(service_dir.join("Dockerfile.orig"), "FROM busybox")?;
+        std::fs::write(service_dir.join("docker-compose.yml"), "services: {}")?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+
+        let output_dir = tempdir()?;
+        let skipped = engine.generate("service", context.clone(), output_dir.path())?;
+        assert_eq!(
+            skipped,
+            vec![
+                PathBuf::from("Dockerfile.orig"),
+                PathBuf::from("docker-compose.yml")
+            ]
+        );
+        assert!(output_dir.path().join("Dockerfile").exists());
+        assert!(!output_dir.path().join("docker-compose.yml").exists());
+
+        let output_dir = tempdir()?;
+        let context = context.with_values([("with_compose".to_string(), Value::from(true))]);
+        let skipped = engine
+            .generate_async("service", context, output_dir.path())
+            .await?;
+        assert_eq!(skipped, vec![PathBuf::from("Dockerfile.orig")]);
+        assert!(output_dir.path().join("docker-compose.yml").exists());
+        assert!(!output_dir.path().join("Dockerfile.orig").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_and_copied_files_are_not_rendered() -> Result<()> {
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        std::fs::create_dir_all(service_dir.join(".github/workflows"))?;
+        std::fs::write(
+            service_dir.join("essex.toml"),
+            "copy = [\".github/workflows\"]\n",
+        )?;
+        let icon = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR{{ image_name }}\xff";
+        std::fs::write(service_dir.join("icon.png"), icon)?;
+        let workflow = "run: docker build -t app:${{ github.sha }} .";
+        std::fs::write(service_dir.join(".github/workflows/ci.yml"), workflow)?;
+        std::fs::write(service_dir.join("README.md"), "# {{ image_name }}")?;
+
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+
+        let output_dir = tempdir()?;
+        engine.generate("service", context.clone(), output_dir.path())?;
+        assert_eq!(std::fs::read(output_dir.path().join("icon.png"))?, icon);
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join(".github/workflows/ci.yml"))?,
+            workflow
+        );
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join("README.md"))?,
+            "# app"
+        );
+
+        let output_dir = tempdir()?;
+        engine
+            .generate_async("service", context, output_dir.path())
+            .await?;
+        assert_eq!(std::fs::read(output_dir.path().join("icon.png"))?, icon);
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join(".github/workflows/ci.yml"))?,
+            workflow
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_and_directory_modes() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let templates_dir = tempdir()?;
+        let service_dir = templates_dir.path().join("service");
+        let bin_dir = service_dir.join("runtime-assets/usr/local/bin");
+        std::fs::create_dir_all(&bin_dir)?;
+        std::fs::create_dir_all(service_dir.join("secrets"))?;
+        std::fs::write(
+            service_dir.join("essex.toml"),
+            "[[modes]]\nglob = \"runtime-assets/usr/local/bin/*\"\nmode = \"0755\"\n\n\
+             [[modes]]\nglob = \"secrets\"\nmode = \"0600\"\ndir_mode = \"0700\"\n",
+        )?;
+        std::fs::write(bin_dir.join("healthcheck"), "#!/bin/sh")?;
+        std::fs::write(service_dir.join("secrets/db.env.example"), "PASSWORD=")?;
+        std::fs::write(service_dir.join("run.sh"), "#!/bin/sh")?;
+        std::fs::write(service_dir.join("tool"), "#!/bin/sh")?;
+        std::fs::write(service_dir.join("README.md"), "# {{ image_name }}")?;
+        std::fs::set_permissions(
+            service_dir.join("tool"),
+            std::fs::Permissions::from_mode(0o750),
+        )?;
+        std::fs::set_permissions(
+            service_dir.join("run.sh"),
+            std::fs::Permissions::from_mode(0o640),
+        )?;
+        std::fs::set_permissions(
+            service_dir.join("README.md"),
+            std::fs::Permissions::from_mode(0o644),
+        )?;
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let engine = TemplateEngine::from_templates_dir(templates_dir.path())?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+
+        let sync_dir = tempdir()?;
+        engine.generate("service", context.clone(), sync_dir.path())?;
+        let async_dir = tempdir()?;
+        engine
+            .generate_async("service", context, async_dir.path())
+            .await?;
+
+        for output_dir in [sync_dir.path(), async_dir.path()] {
+            let bin = output_dir.join("runtime-assets/usr/local/bin/healthcheck");
+            assert_eq!(mode(&bin), 0o755);
+            assert_eq!(mode(&output_dir.join("secrets/db.env.example")), 0o600);
+            assert_eq!(mode(&output_dir.join("secrets")), 0o700);
+            assert_eq!(mode(&output_dir.join("tool")), 0o750);
+            assert_eq!(mode(&output_dir.join("run.sh")), 0o750);
+            assert_eq!(mode(&output_dir.join("README.md")), 0o644);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_path_rejects_escaping_paths() {
+        let render = |path: &str, value: &str| {
+            let mut context = Context::new();
+            context.insert("name", value);
+            render_path(Path::new(path), |segment| {
+                Tera::one_off(segment, &context, false)
+            })
+        };
+
+        assert_eq!(
+            render("src/{{ name }}/main.py", "app").unwrap(),
+            PathBuf::from("src/app/main.py")
+        );
+        assert!(render("src/{{ name }}/main.py", "").is_err());
+        assert!(render("src/{{ name }}/main.py", "  ").is_err());
+        assert!(render("{{ name }}/main.py", "..").is_err());
+        assert!(render("{{ name }}/passwd", "../../etc").is_err());
+        assert!(render("{{ name }}", "/etc/passwd").is_err());
+        assert!(render("src/{{ missing }}", "app").is_err());
+    }
+
+    #[test]
+    fn test_missing_templates_dir() {
+        let result = TemplateEngine::from_templates_dir("/nonexistent/templates");
+        assert!(matches!(result, Err(Error::InvalidPath(_))));
+    }
+
     #[test]
     fn test_template_not_found() {
         let engine = TemplateEngine::new(".").unwrap();
         let templates = engine.list_templates().unwrap();
         assert!(!templates.contains(&"non_existent".to_string()));
+        // The argument of `new` is not a templates directory, so `src` is not a template
+        assert!(!templates.contains(&"src".to_string()));
     }
 }
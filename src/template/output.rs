@@ -0,0 +1,396 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::pipeline::write_file;
+use super::{set_dir_modes, Rendered, Staging};
+use crate::error::{Error, Result};
+
+/// Where a generated project goes: the filesystem, memory, or an archive stream.
+///
+/// Files are added one at a time with paths relative to the project root, followed by the
+/// modes of any directories the template declares, and then [`Output::finish`] is called.
+pub trait Output {
+    /// Add a file to the project
+    fn add_file(&mut self, path: &Path, contents: &[u8], mode: u32) -> Result<()>;
+
+    /// Set the mode of a directory that holds files already added
+    fn set_dir_mode(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Complete the project once every file has been added
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Send rendered files to an output and finish it
+pub fn write_output<O: Output + ?Sized>(rendered: &Rendered, output: &mut O) -> Result<()> {
+    for file in &rendered.files {
+        output.add_file(&file.path, &file.contents, file.mode)?;
+    }
+    for (dir, mode) in &rendered.dir_modes {
+        output.set_dir_mode(dir, *mode)?;
+    }
+    output.finish()
+}
+
+/// A file held by [`MemoryOutput`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryFile {
+    pub contents: Vec<u8>,
+    pub mode: u32,
+}
+
+/// Keeps the generated project in memory, by path
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOutput {
+    pub files: BTreeMap<PathBuf, MemoryFile>,
+    pub dir_modes: BTreeMap<PathBuf, u32>,
+}
+
+impl MemoryOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Output for MemoryOutput {
+    fn add_file(&mut self, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+        self.files.insert(
+            path.to_path_buf(),
+            MemoryFile {
+                contents: contents.to_vec(),
+                mode,
+            },
+        );
+        Ok(())
+    }
+
+    fn set_dir_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.dir_modes.insert(path.to_path_buf(), mode);
+        Ok(())
+    }
+}
+
+/// Writes the project to a directory, through a staging directory so that nothing appears
+/// there unless generation finishes
+pub struct FsOutput {
+    staging: Option<Staging>,
+    root: PathBuf,
+    dir_modes: BTreeMap<PathBuf, u32>,
+}
+
+impl FsOutput {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        Ok(Self {
+            staging: Some(Staging::new(&root)?),
+            root,
+            dir_modes: BTreeMap::new(),
+        })
+    }
+
+    fn finished(&self) -> Error {
+        Error::InvalidPath(format!("{}: output already finished", self.root.display()))
+    }
+}
+
+impl Output for FsOutput {
+    fn add_file(&mut self, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+        let staging = self.staging.as_ref().ok_or_else(|| self.finished())?;
+        write_file(staging.path(), path, contents, mode)
+    }
+
+    fn set_dir_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.dir_modes.insert(path.to_path_buf(), mode);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let staging = self.staging.take().ok_or_else(|| self.finished())?;
+        staging.commit()?;
+        set_dir_modes(&self.dir_modes, &self.root)
+    }
+}
+
+/// Streams the project as a tar archive, optionally gzipped, into any writer
+pub struct TarOutput<W: Write> {
+    builder: tar::Builder<TarWriter<W>>,
+    mtime: u64,
+}
+
+enum TarWriter<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+}
+
+impl<W: Write> Write for TarWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TarWriter::Plain(writer) => writer.write(buf),
+            TarWriter::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TarWriter::Plain(writer) => writer.flush(),
+            TarWriter::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+impl<W: Write> TarOutput<W> {
+    /// An uncompressed tar stream
+    pub fn new(writer: W) -> Self {
+        Self::with_writer(TarWriter::Plain(writer))
+    }
+
+    /// A gzipped tar stream, as in `.tar.gz`
+    pub fn gzip(writer: W) -> Self {
+        Self::with_writer(TarWriter::Gzip(GzEncoder::new(
+            writer,
+            Compression::default(),
+        )))
+    }
+
+    fn with_writer(writer: TarWriter<W>) -> Self {
+        Self {
+            builder: tar::Builder::new(writer),
+            mtime: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+
+    /// Complete the archive and return the writer
+    pub fn into_inner(self) -> Result<W> {
+        match self.builder.into_inner()? {
+            TarWriter::Plain(writer) => Ok(writer),
+            TarWriter::Gzip(encoder) => Ok(encoder.finish()?),
+        }
+    }
+
+    fn header(&self, kind: tar::EntryType, size: u64, mode: u32) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(size);
+        header.set_mode(mode);
+        header.set_mtime(self.mtime);
+        header
+    }
+}
+
+impl<W: Write> Output for TarOutput<W> {
+    fn add_file(&mut self, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+        let mut header = self.header(tar::EntryType::Regular, contents.len() as u64, mode);
+        self.builder
+            .append_data(&mut header, path, contents)
+            .map_err(Error::from)
+    }
+
+    fn set_dir_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        let mut header = self.header(tar::EntryType::Directory, 0, mode);
+        self.builder
+            .append_data(&mut header, path, std::io::empty())
+            .map_err(Error::from)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.builder.finish().map_err(Error::from)
+    }
+}
+
+/// Writes the project as a zip archive. Zip archives end with an index of their entries,
+/// so the writer must be seekable, such as a `Cursor<Vec<u8>>` or a file.
+pub struct ZipOutput<W: Write + Seek> {
+    writer: ZipWriter<W>,
+}
+
+impl<W: Write + Seek> ZipOutput<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: ZipWriter::new(writer),
+        }
+    }
+
+    /// Complete the archive and return the writer
+    pub fn into_inner(self) -> Result<W> {
+        self.writer.finish().map_err(zip_error)
+    }
+}
+
+impl<W: Write + Seek> Output for ZipOutput<W> {
+    fn add_file(&mut self, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(mode);
+        self.writer
+            .start_file(archive_name(path), options)
+            .map_err(zip_error)?;
+        self.writer.write_all(contents)?;
+        Ok(())
+    }
+
+    fn set_dir_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        let options = SimpleFileOptions::default().unix_permissions(mode);
+        self.writer
+            .add_directory(archive_name(path), options)
+            .map_err(zip_error)
+    }
+}
+
+/// Name of an archive entry: the path with `/` separators
+fn archive_name(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn zip_error(error: zip::result::ZipError) -> Error {
+    Error::IoError(error.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::RenderedFile;
+    use flate2::read::GzDecoder;
+    use std::io::{Cursor, Read};
+    use tempfile::tempdir;
+
+    fn rendered() -> Rendered {
+        Rendered {
+            files: vec![
+                RenderedFile {
+                    path: PathBuf::from("README.md"),
+                    contents: b"# app\n".to_vec(),
+                    mode: 0o644,
+                },
+                RenderedFile {
+                    path: PathBuf::from("bin/run.sh"),
+                    contents: b"#!/bin/sh\n".to_vec(),
+                    mode: 0o755,
+                },
+            ],
+            dir_modes: BTreeMap::from([(PathBuf::from("bin"), 0o750)]),
+            ..Rendered::default()
+        }
+    }
+
+    #[test]
+    fn test_memory_output() -> Result<()> {
+        let mut output = MemoryOutput::new();
+        write_output(&rendered(), &mut output)?;
+        assert_eq!(
+            output.files[Path::new("bin/run.sh")],
+            MemoryFile {
+                contents: b"#!/bin/sh\n".to_vec(),
+                mode: 0o755
+            }
+        );
+        assert_eq!(output.dir_modes[Path::new("bin")], 0o750);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_output() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("app");
+        let mut output = FsOutput::new(&root)?;
+        output.add_file(Path::new("README.md"), b"# app\n", 0o644)?;
+        assert!(!root.exists());
+
+        let mut output = FsOutput::new(&root)?;
+        write_output(&rendered(), &mut output)?;
+        assert_eq!(std::fs::read(root.join("bin/run.sh"))?, b"#!/bin/sh\n");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &str| {
+                std::fs::metadata(root.join(path))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o777
+            };
+            assert_eq!(mode("bin/run.sh"), 0o755);
+            assert_eq!(mode("bin"), 0o750);
+        }
+
+        assert!(output.finish().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_output_rejects_paths_outside_the_root() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("app");
+        let mut output = FsOutput::new(&root)?;
+        let escaped = dir.path().join("escaped");
+        for path in [
+            Path::new("../escaped"),
+            escaped.as_path(),
+            Path::new("a/../b"),
+        ] {
+            assert!(matches!(
+                output.add_file(path, b"x", 0o644),
+                Err(Error::InvalidPath(_))
+            ));
+        }
+        assert!(!escaped.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_output() -> Result<()> {
+        let mut output = TarOutput::gzip(Vec::new());
+        write_output(&rendered(), &mut output)?;
+        let bytes = output.into_inner()?;
+
+        let mut archive = tar::Archive::new(GzDecoder::new(bytes.as_slice()));
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            entries.push((
+                entry.path()?.display().to_string(),
+                entry.header().mode()?,
+                contents,
+            ));
+        }
+        assert_eq!(
+            entries,
+            vec![
+                ("README.md".to_string(), 0o644, "# app\n".to_string()),
+                ("bin/run.sh".to_string(), 0o755, "#!/bin/sh\n".to_string()),
+                ("bin".to_string(), 0o750, String::new()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip_output() -> Result<()> {
+        let mut output = ZipOutput::new(Cursor::new(Vec::new()));
+        write_output(&rendered(), &mut output)?;
+        let cursor = output.into_inner()?;
+
+        let mut archive = zip::ZipArchive::new(cursor).map_err(zip_error)?;
+        let mut script = archive.by_name("bin/run.sh").map_err(zip_error)?;
+        let mut contents = String::new();
+        script.read_to_string(&mut contents)?;
+        assert_eq!(contents, "#!/bin/sh\n");
+        assert_eq!(script.unix_mode().map(|mode| mode & 0o777), Some(0o755));
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tera::{Context, Tera};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use super::{
+    collect_dir_modes, duplicate_path_error, file_mode, registration_error, render_error,
+    render_path, stays_inside, template_name, template_text, Rendered, RenderedFile, Template,
+    TemplateContext,
+};
+use crate::error::{Error, Result};
+
+/// How many files are rendered or written at once unless the engine is told otherwise
+pub fn default_parallelism() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// One file of the generated project
+#[derive(Debug, Clone)]
+pub struct RenderJob {
+    /// Template file the job comes from
+    pub source: PathBuf,
+    /// Rendered path inside the project
+    pub path: PathBuf,
+    pub mode: u32,
+    pub action: JobAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum JobAction {
+    /// Render the template file, registered in the plan's Tera instance under its path
+    Render,
+    /// Copy these bytes as they are
+    Copy(Vec<u8>),
+}
+
+/// Everything needed to generate a template, worked out before any file is rendered: which
+/// files are generated and where, with which modes, and which are skipped
+pub struct Plan {
+    template: String,
+    tera: Arc<Tera>,
+    context: Arc<Context>,
+    /// Text of the files to render, to point errors at the failing line
+    sources: Arc<HashMap<PathBuf, String>>,
+    pub jobs: Vec<RenderJob>,
+    /// Template files left out by the manifest's `exclude` and `include_if` rules
+    pub skipped: Vec<PathBuf>,
+    /// Rendered paths of the files copied without rendering
+    pub copied: Vec<PathBuf>,
+    /// Modes the manifest declares for generated directories, by rendered path
+    pub dir_modes: BTreeMap<PathBuf, u32>,
+}
+
+impl Plan {
+    /// Plan the generation of `template`, resolving the context against its manifest and
+    /// registering its text files on a copy of `registry`
+    pub fn new(registry: &Tera, template: &Template, context: TemplateContext) -> Result<Self> {
+        Self::build(registry, template, context).map_err(|e| e.in_template(&template.name))
+    }
+
+    fn build(registry: &Tera, template: &Template, context: TemplateContext) -> Result<Self> {
+        context.validate()?;
+        // Convert context to Tera context, checking the template's declared variables
+        let context = template.manifest.resolve(context.into_context())?;
+        let manifest = &template.manifest;
+        let mut tera = registry.clone();
+
+        let mut jobs = Vec::with_capacity(template.files.len());
+        let mut sources = Vec::new();
+        let mut seen = HashSet::new();
+        let mut skipped = Vec::new();
+        let mut copied = Vec::new();
+        let mut dir_modes = BTreeMap::new();
+        for file in &template.files {
+            let included =
+                manifest.includes(&file.path, |condition| tera.render_str(condition, &context))?;
+            if !included {
+                skipped.push(file.path.clone());
+                continue;
+            }
+
+            let path = render_path(&file.path, |segment| tera.render_str(segment, &context))?;
+            if !seen.insert(path.clone()) {
+                return Err(duplicate_path_error(&file.path, &path));
+            }
+
+            collect_dir_modes(
+                manifest,
+                file,
+                |segment| tera.render_str(segment, &context),
+                &mut dir_modes,
+            )?;
+
+            let action = match template_text(manifest, file)? {
+                Some(content) => {
+                    sources.push((file.path.clone(), content));
+                    JobAction::Render
+                }
+                None => {
+                    copied.push(path.clone());
+                    JobAction::Copy(file.contents.clone())
+                }
+            };
+            jobs.push(RenderJob {
+                source: file.path.clone(),
+                mode: file_mode(manifest, file, &path)?,
+                path,
+                action,
+            });
+        }
+
+        // Register every file before rendering any, so that files of the template can
+        // include each other whatever their order
+        tera.add_raw_templates(
+            sources
+                .iter()
+                .map(|(path, content)| (template_name(path), *content)),
+        )
+        .map_err(|e| {
+            let files = sources
+                .iter()
+                .map(|(path, content)| (path.as_path(), *content));
+            registration_error(files, e)
+        })?;
+
+        Ok(Self {
+            template: template.name.clone(),
+            tera: Arc::new(tera),
+            context: Arc::new(context),
+            sources: Arc::new(
+                sources
+                    .into_iter()
+                    .map(|(path, content)| (path, content.to_string()))
+                    .collect(),
+            ),
+            jobs,
+            skipped,
+            copied,
+            dir_modes,
+        })
+    }
+
+    /// Render every job, `parallelism` files at a time
+    pub async fn render(self, parallelism: usize) -> Result<Rendered> {
+        let tera = self.tera;
+        let context = self.context;
+        let sources = self.sources;
+        let files = run_bounded(self.jobs, parallelism, move |job| {
+            let tera = Arc::clone(&tera);
+            let context = Arc::clone(&context);
+            let sources = Arc::clone(&sources);
+            async move {
+                let task =
+                    tokio::task::spawn_blocking(move || render_job(&tera, &context, &sources, job));
+                task.await.map_err(|e| Error::IoError(e.into()))?
+            }
+        })
+        .await
+        .map_err(|e| e.in_template(&self.template))?;
+
+        Ok(Rendered {
+            files,
+            skipped: self.skipped,
+            copied: self.copied,
+            dir_modes: self.dir_modes,
+            conflicts: Vec::new(),
+        })
+    }
+}
+
+fn render_job(
+    tera: &Tera,
+    context: &Context,
+    sources: &HashMap<PathBuf, String>,
+    job: RenderJob,
+) -> Result<RenderedFile> {
+    let contents = match job.action {
+        JobAction::Render => tera
+            .render(&template_name(&job.source), context)
+            .map_err(|e| {
+                let source = sources.get(&job.source).map(String::as_str);
+                render_error(&job.source, source, e)
+            })?
+            .into_bytes(),
+        JobAction::Copy(contents) => contents,
+    };
+    Ok(RenderedFile {
+        path: job.path,
+        contents,
+        mode: job.mode,
+    })
+}
+
+/// Write rendered files into `to`, `parallelism` files at a time, replacing files with the
+/// same path
+pub async fn write(files: &[RenderedFile], to: &Path, parallelism: usize) -> Result<()> {
+    let to = Arc::new(to.to_path_buf());
+    run_bounded(files.to_vec(), parallelism, move |file| {
+        let to = Arc::clone(&to);
+        async move {
+            let task = tokio::task::spawn_blocking(move || {
+                write_file(&to, &file.path, &file.contents, file.mode)
+            });
+            task.await.map_err(|e| Error::IoError(e.into()))?
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+/// Write one file of a project, creating its parent directories. `path` is relative to the
+/// directory `to`, and must stay inside it.
+pub(crate) fn write_file(to: &Path, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    if !stays_inside(path) {
+        return Err(Error::InvalidPath(format!(
+            "'{}' is outside the output directory",
+            path.display()
+        )));
+    }
+    let output_path = to.join(path);
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| Error::io_at(parent, e))?;
+    }
+    std::fs::write(&output_path, contents).map_err(|e| Error::io_at(&output_path, e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&output_path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| Error::io_at(&output_path, e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+/// Run a task for every item with at most `parallelism` running at once. Results keep the
+/// order of the items, and when tasks fail the error of the first failing item is returned.
+async fn run_bounded<I, T, F, Fut>(items: Vec<I>, parallelism: usize, task: F) -> Result<Vec<T>>
+where
+    F: Fn(I) -> Fut,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(parallelism.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let permit = Arc::clone(&semaphore)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let future = task(item);
+        tasks.spawn(async move {
+            let result = future.await;
+            drop(permit);
+            (index, result)
+        });
+    }
+
+    let mut results = Vec::with_capacity(tasks.len());
+    while let Some(joined) = tasks.join_next().await {
+        results.push(joined.map_err(|e| Error::IoError(e.into()))?);
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Run a future to completion from synchronous code. The future runs on a runtime of its
+/// own, on a thread of its own, so this also works when called from async code.
+pub fn block_on<F, T>(future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>> + Send,
+    T: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(future)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::TemplateFile;
+    use crate::template::TemplateSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_run_bounded_limits_parallelism_and_keeps_order() -> Result<()> {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let results = run_bounded((0..16).collect(), 3, |n: u64| {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(16 - n)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(n * 2)
+            }
+        })
+        .await?;
+
+        assert_eq!(results, (0..16).map(|n| n * 2).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+
+        let err = run_bounded(vec![1, 2, 3], 3, |n| async move {
+            match n {
+                1 => Ok(n),
+                _ => Err(Error::InvalidPath(n.to_string())),
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            Error::InvalidPath("2".to_string()).to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_and_render() -> Result<()> {
+        let files = vec![
+            TemplateFile {
+                path: PathBuf::from("README.md"),
+                contents: b"{% include \"docs/intro.md\" %} {{ image_name }}".to_vec(),
+                mode: None,
+            },
+            TemplateFile {
+                path: PathBuf::from("docs/intro.md"),
+                contents: b"Welcome to".to_vec(),
+                mode: None,
+            },
+            TemplateFile {
+                path: PathBuf::from("logo.png"),
+                contents: vec![0x89, b'P', b'N', b'G', 0],
+                mode: None,
+            },
+        ];
+        let template = Template::new("docs", TemplateSource::Builtin, files)?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+
+        let plan = Plan::new(&Tera::default(), &template, context)?;
+        assert!(matches!(plan.jobs[0].action, JobAction::Render));
+        assert!(matches!(plan.jobs[2].action, JobAction::Copy(_)));
+
+        let rendered = block_on(plan.render(2))?;
+        assert_eq!(rendered.files[0].contents, b"Welcome to app");
+        assert_eq!(rendered.files[1].contents, b"Welcome to");
+        assert_eq!(rendered.files[2].contents, vec![0x89, b'P', b'N', b'G', 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_errors_name_the_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("docs"), "not a directory")?;
+        let files = [RenderedFile {
+            path: PathBuf::from("docs/intro.md"),
+            contents: b"Welcome".to_vec(),
+            mode: 0o644,
+        }];
+
+        let error = block_on(write(&files, dir.path(), 2)).unwrap_err();
+        assert!(matches!(error, Error::IoError(_)));
+        assert!(error
+            .to_string()
+            .contains(&dir.path().join("docs").display().to_string()));
+        Ok(())
+    }
+}
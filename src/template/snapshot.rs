@@ -0,0 +1,361 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use super::values::parse_values;
+use super::{write_files, Rendered, RenderedFile, Template, TemplateContext, TemplateFile, Values};
+use crate::error::{Error, Result};
+
+/// Directory of a template that holds its test cases
+pub const TESTS_DIR: &str = "tests";
+
+/// Directory of a test case that holds the files the template should produce
+pub const EXPECTED_DIR: &str = "expected";
+
+/// Files a test case's answers are read from, in order of preference
+const ANSWERS_FILES: [&str; 4] = [
+    "answers.yaml",
+    "answers.yml",
+    "answers.json",
+    "answers.toml",
+];
+
+/// Project name test cases are rendered for when their answers do not give one
+pub const DEFAULT_PROJECT: &str = "example/project";
+
+/// Build date test cases are rendered with unless their answers set one, so that expected
+/// files do not change from one run to the next
+pub const TEST_BUILD_DATE: &str = "2024-01-01T00:00:00Z";
+
+/// Directories of a template's test cases, such as `tests/default`: the directories right
+/// below `tests/` that hold an answers file. Other files under `tests/` are template files.
+pub fn case_dirs(files: &[TemplateFile]) -> BTreeSet<PathBuf> {
+    files
+        .iter()
+        .filter_map(|file| {
+            let mut components = file.path.components();
+            if components.next()?.as_os_str() != TESTS_DIR {
+                return None;
+            }
+            let case = components.next()?;
+            let name = components.next()?.as_os_str().to_str()?;
+            if components.next().is_some() || !ANSWERS_FILES.contains(&name) {
+                return None;
+            }
+            Some(Path::new(TESTS_DIR).join(case))
+        })
+        .collect()
+}
+
+/// A test case of a template: the answers to render it with, and the files it should produce
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    /// Project name, as given to `essex new`
+    pub project: String,
+    pub username: Option<String>,
+    pub vendor: Option<String>,
+    pub values: Values,
+    /// Expected files, with paths relative to the case's `expected` directory
+    pub expected: Vec<TemplateFile>,
+}
+
+impl TestCase {
+    /// The context the case is rendered with
+    pub fn context(&self) -> Result<TemplateContext> {
+        let mut context =
+            TemplateContext::new(&self.project, self.username.clone(), self.vendor.clone())?;
+        context.build_date = TEST_BUILD_DATE.to_string();
+        Ok(context.with_values(self.values.clone()))
+    }
+
+    /// Compare rendered files to the expected ones by contents, in path order
+    pub fn compare(&self, rendered: &[RenderedFile]) -> Vec<Mismatch> {
+        let expected: BTreeMap<&Path, &[u8]> = self
+            .expected
+            .iter()
+            .map(|file| (file.path.as_path(), file.contents.as_slice()))
+            .collect();
+        let rendered: BTreeMap<&Path, &[u8]> = rendered
+            .iter()
+            .map(|file| (file.path.as_path(), file.contents.as_slice()))
+            .collect();
+        let paths: BTreeSet<&Path> = expected.keys().chain(rendered.keys()).copied().collect();
+
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let (expected, rendered) =
+                    (expected.get(path).copied(), rendered.get(path).copied());
+                let kind = match (expected, rendered) {
+                    (Some(expected), Some(rendered)) if expected == rendered => return None,
+                    (Some(_), Some(_)) => MismatchKind::Changed,
+                    (Some(_), None) => MismatchKind::Missing,
+                    (None, _) => MismatchKind::Unexpected,
+                };
+                Some(Mismatch {
+                    path: path.to_path_buf(),
+                    kind,
+                    diff: diff(path, expected, rendered),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read the test cases of a template from its `tests/` directory
+pub fn test_cases(template: &Template) -> Result<Vec<TestCase>> {
+    let mut cases = Vec::new();
+    for dir in case_dirs(&template.test_files) {
+        let answers = ANSWERS_FILES
+            .iter()
+            .find_map(|name| {
+                let path = dir.join(name);
+                template.test_files.iter().find(|file| file.path == path)
+            })
+            .expect("case directories hold an answers file");
+        let invalid = |message: &str| {
+            Error::InvalidValues(format!("{}: {}", answers.path.display(), message))
+        };
+
+        let content = std::str::from_utf8(&answers.contents)
+            .map_err(|_| invalid("answers are not valid UTF-8"))?;
+        let mut values = parse_values(content, &answers.path)?;
+        let mut text = |key: &str| match values.remove(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(invalid(&format!("`{}` must be a string", key))),
+        };
+        let project = text("project")?.unwrap_or_else(|| DEFAULT_PROJECT.to_string());
+        let username = text("username")?;
+        let vendor = text("vendor")?;
+
+        let expected_dir = dir.join(EXPECTED_DIR);
+        let expected = template
+            .test_files
+            .iter()
+            .filter_map(|file| {
+                let path = file.path.strip_prefix(&expected_dir).ok()?;
+                Some(TemplateFile {
+                    path: path.to_path_buf(),
+                    ..file.clone()
+                })
+            })
+            .collect();
+
+        cases.push(TestCase {
+            name: dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            project,
+            username,
+            vendor,
+            values,
+            expected,
+        });
+    }
+    Ok(cases)
+}
+
+/// How a test case turned out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaseStatus {
+    Passed,
+    Failed,
+    /// The case did not match, and its expected files were replaced with the rendered ones
+    Blessed,
+}
+
+/// The outcome of rendering a test case and comparing it to its expected files
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub name: String,
+    pub status: CaseStatus,
+    /// Why the case could not be rendered
+    pub error: Option<String>,
+    pub mismatches: Vec<Mismatch>,
+    #[serde(skip)]
+    pub rendered: Vec<RenderedFile>,
+}
+
+impl CaseResult {
+    pub fn new(case: &TestCase, rendered: Result<Rendered>) -> Self {
+        let (error, mismatches, rendered) = match rendered {
+            Ok(rendered) => (None, case.compare(&rendered.files), rendered.files),
+            Err(e) => (Some(e.to_string()), Vec::new(), Vec::new()),
+        };
+        let status = if error.is_none() && mismatches.is_empty() {
+            CaseStatus::Passed
+        } else {
+            CaseStatus::Failed
+        };
+        Self {
+            name: case.name.clone(),
+            status,
+            error,
+            mismatches,
+            rendered,
+        }
+    }
+}
+
+/// How a rendered file differs from the expected one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MismatchKind {
+    /// Expected but not rendered
+    Missing,
+    /// Rendered but not expected
+    Unexpected,
+    /// Rendered with different contents
+    Changed,
+}
+
+/// A file that does not match its expected version
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub kind: MismatchKind,
+    /// Unified diff from the expected file to the rendered one
+    pub diff: String,
+}
+
+fn diff(path: &Path, expected: Option<&[u8]>, rendered: Option<&[u8]>) -> String {
+    let name = |dir: &str, contents: Option<&[u8]>| match contents {
+        Some(_) => format!("{}/{}", dir, path.display()),
+        None => "/dev/null".to_string(),
+    };
+    let (old_name, new_name) = (name(EXPECTED_DIR, expected), name("rendered", rendered));
+
+    match (
+        std::str::from_utf8(expected.unwrap_or_default()),
+        std::str::from_utf8(rendered.unwrap_or_default()),
+    ) {
+        (Ok(expected), Ok(rendered)) => diffy::DiffOptions::new()
+            .set_original_filename(old_name)
+            .set_modified_filename(new_name)
+            .create_patch(expected, rendered)
+            .to_string(),
+        _ => format!("Binary files {} and {} differ\n", old_name, new_name),
+    }
+}
+
+/// Replace the expected files of test case `name`, in the template directory `dir`, with the
+/// rendered files
+pub fn bless(dir: &Path, name: &str, rendered: &[RenderedFile]) -> Result<()> {
+    let expected = dir.join(TESTS_DIR).join(name).join(EXPECTED_DIR);
+    if expected.exists() {
+        std::fs::remove_dir_all(&expected)?;
+    }
+    write_files(rendered, &expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::TemplateSource;
+    use serde_json::json;
+
+    fn file(path: &str, contents: &str) -> TemplateFile {
+        TemplateFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+            mode: None,
+        }
+    }
+
+    fn rendered(path: &str, contents: &str) -> RenderedFile {
+        RenderedFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+            mode: 0o644,
+        }
+    }
+
+    #[test]
+    fn test_test_cases() {
+        let template = Template::new(
+            "svc",
+            TemplateSource::Builtin,
+            vec![
+                file("README.md", "# {{ image_name }}\n"),
+                file("tests/test_app.py", "def test(): pass\n"),
+                file(
+                    "tests/default/answers.yaml",
+                    "project: acme/api\nport: 9090\n",
+                ),
+                file("tests/default/expected/README.md", "# api\n"),
+                file("tests/minimal/answers.json", "{}"),
+            ],
+        )
+        .unwrap();
+
+        let paths: Vec<&Path> = template.files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("README.md"), Path::new("tests/test_app.py")]
+        );
+
+        let cases = test_cases(&template).unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].name, "default");
+        assert_eq!(cases[0].project, "acme/api");
+        assert_eq!(cases[0].values["port"], json!(9090));
+        assert_eq!(cases[0].expected[0].path, PathBuf::from("README.md"));
+        assert_eq!(cases[1].project, DEFAULT_PROJECT);
+        assert!(cases[1].expected.is_empty());
+
+        let context = cases[0].context().unwrap();
+        assert_eq!(context.image_name, "api");
+        assert_eq!(context.build_date, TEST_BUILD_DATE);
+    }
+
+    #[test]
+    fn test_compare() {
+        let case = TestCase {
+            name: "default".to_string(),
+            project: DEFAULT_PROJECT.to_string(),
+            username: None,
+            vendor: None,
+            values: Values::new(),
+            expected: vec![
+                file("Makefile", "build:\n\tdocker build .\n"),
+                file("README.md", "# api\n"),
+                file("old.txt", "gone\n"),
+            ],
+        };
+        let mismatches = case.compare(&[
+            rendered("Makefile", "build:\n\tdocker build -t api .\n"),
+            rendered("README.md", "# api\n"),
+            rendered("new.txt", "new\n"),
+        ]);
+
+        let kinds: Vec<(&str, MismatchKind)> = mismatches
+            .iter()
+            .map(|m| (m.path.to_str().unwrap(), m.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Makefile", MismatchKind::Changed),
+                ("new.txt", MismatchKind::Unexpected),
+                ("old.txt", MismatchKind::Missing),
+            ]
+        );
+        assert_eq!(
+            mismatches[0].diff,
+            "--- expected/Makefile\n+++ rendered/Makefile\n@@ -1,2 +1,2 @@\n build:\n\
+             -\tdocker build .\n+\tdocker build -t api .\n"
+        );
+        assert!(mismatches[1]
+            .diff
+            .starts_with("--- /dev/null\n+++ rendered/new.txt\n"));
+        assert!(mismatches[2]
+            .diff
+            .starts_with("--- expected/old.txt\n+++ /dev/null\n"));
+    }
+}
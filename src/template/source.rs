@@ -0,0 +1,323 @@
+use include_dir::{Dir, DirEntry};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use super::manifest::{Manifest, MANIFEST_FILE};
+use super::snapshot;
+use crate::error::{Diagnostic, Error, Result};
+
+/// Where a template was loaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateSource {
+    /// Compiled into the binary from the `templates/` directory.
+    Builtin,
+    /// Loaded from a directory on the local filesystem.
+    Local(PathBuf),
+    /// Read from a `.tar.gz` or `.zip` archive.
+    Archive(PathBuf),
+    /// Checked out from a git repository at a resolved commit.
+    Git { url: String, commit: String },
+}
+
+impl TemplateSource {
+    /// The commit the template was checked out at, for templates loaded from git
+    pub fn commit(&self) -> Option<&str> {
+        match self {
+            TemplateSource::Git { commit, .. } => Some(commit),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateSource::Builtin => write!(f, "built-in"),
+            TemplateSource::Local(path) => write!(f, "{}", path.display()),
+            TemplateSource::Archive(path) => write!(f, "{}", path.display()),
+            TemplateSource::Git { url, commit } => write!(f, "{}@{}", url, commit),
+        }
+    }
+}
+
+/// A single file of a template, with its path relative to the template root.
+#[derive(Debug, Clone)]
+pub struct TemplateFile {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+    /// Permissions of the source file, when the source records them
+    pub mode: Option<u32>,
+}
+
+impl TemplateFile {
+    /// Whether the file looks binary: not valid UTF-8, or a NUL byte near the start as git
+    /// checks for
+    pub fn is_binary(&self) -> bool {
+        let head = &self.contents[..self.contents.len().min(8000)];
+        head.contains(&0) || std::str::from_utf8(&self.contents).is_err()
+    }
+}
+
+/// A template loaded into memory, ready to be rendered.
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub source: TemplateSource,
+    pub manifest: Manifest,
+    pub files: Vec<TemplateFile>,
+    /// Scripts run by the manifest's hooks, kept out of the generated project
+    pub hook_files: Vec<TemplateFile>,
+    /// Test cases under `tests/`, kept out of the generated project
+    pub test_files: Vec<TemplateFile>,
+}
+
+impl Template {
+    /// Build a template from its files, splitting off the manifest if there is one
+    pub fn new(name: &str, source: TemplateSource, mut files: Vec<TemplateFile>) -> Result<Self> {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let manifest = match files
+            .iter()
+            .position(|f| f.path == Path::new(MANIFEST_FILE))
+        {
+            Some(index) => {
+                let file = files.remove(index);
+                let content =
+                    std::str::from_utf8(&file.contents).map_err(|e| utf8_error(name, &file, e))?;
+                Manifest::parse(content)?
+            }
+            None => Manifest::default(),
+        };
+
+        let scripts: Vec<&Path> = manifest.hooks.scripts().collect();
+        let (hook_files, files): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .partition(|f| scripts.contains(&f.path.as_path()));
+        if let Some(missing) = scripts
+            .iter()
+            .find(|script| !hook_files.iter().any(|f| f.path == **script))
+        {
+            return Err(Error::InvalidTemplate(format!(
+                "hook script '{}' not found in template",
+                missing.display()
+            )));
+        }
+
+        let cases = snapshot::case_dirs(&files);
+        let (test_files, files) = files
+            .into_iter()
+            .partition(|f| cases.iter().any(|dir| f.path.starts_with(dir)));
+
+        Ok(Self {
+            name: name.to_string(),
+            source,
+            manifest,
+            files,
+            hook_files,
+            test_files,
+        })
+    }
+
+    /// Load a template from a directory embedded in the binary
+    pub fn from_embedded(name: &str, dir: &Dir) -> Result<Self> {
+        let mut files = Vec::new();
+        collect_embedded_files(dir, dir.path(), &mut files)?;
+        Self::new(name, TemplateSource::Builtin, files)
+    }
+
+    /// Load a template from a directory on the local filesystem
+    pub fn from_dir(name: &str, dir: &Path) -> Result<Self> {
+        let files = read_dir_files(dir)?;
+        Self::new(name, TemplateSource::Local(dir.to_path_buf()), files)
+    }
+}
+
+/// Point at the first invalid byte of a file that must be UTF-8 text
+fn utf8_error(template: &str, file: &TemplateFile, error: std::str::Utf8Error) -> Error {
+    Error::Diagnostic(Box::new(utf8_diagnostic(
+        template,
+        file,
+        error.valid_up_to(),
+    )))
+}
+
+/// Diagnostic for a file whose bytes stop being UTF-8 at `valid_up_to`
+pub(super) fn utf8_diagnostic(
+    template: &str,
+    file: &TemplateFile,
+    valid_up_to: usize,
+) -> Diagnostic {
+    let valid = String::from_utf8_lossy(&file.contents[..valid_up_to]);
+    let line = valid.lines().count().max(1) + usize::from(valid.ends_with('\n'));
+    let start = valid.rsplit('\n').next().unwrap_or_default();
+    Diagnostic {
+        template: Some(template.to_string()),
+        file: file.path.clone(),
+        line: Some(line),
+        column: Some(start.chars().count() + 1),
+        message: format!("not valid UTF-8 (byte {})", valid_up_to),
+        snippet: Some(start.to_string()),
+    }
+}
+
+/// Read every file below `dir`, with paths relative to it
+fn read_dir_files(dir: &Path) -> Result<Vec<TemplateFile>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && is_vcs_dir(e.file_name())))
+    {
+        let entry = entry.map_err(|e| Error::InvalidPath(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().strip_prefix(dir)?.to_path_buf();
+        let contents = std::fs::read(entry.path())?;
+        let mode = file_mode(
+            &entry
+                .metadata()
+                .map_err(|e| Error::InvalidPath(e.to_string()))?,
+        );
+        files.push(TemplateFile {
+            path,
+            contents,
+            mode,
+        });
+    }
+    Ok(files)
+}
+
+fn collect_embedded_files(dir: &Dir, root: &Path, files: &mut Vec<TemplateFile>) -> Result<()> {
+    for entry in dir.entries() {
+        match entry {
+            DirEntry::Dir(subdir) => collect_embedded_files(subdir, root, files)?,
+            DirEntry::File(file) => files.push(TemplateFile {
+                path: file.path().strip_prefix(root)?.to_path_buf(),
+                contents: file.contents().to_vec(),
+                mode: None,
+            }),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Whether a directory in a templates directory holds shared partials and base templates
+/// (such as `_partials` or `_base`) rather than a template
+pub fn is_shared_dir(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// Directories version control systems keep their data in
+const VCS_DIRS: [&str; 4] = [".git", ".hg", ".svn", ".bzr"];
+
+/// Whether a directory holds version control data, such as `.git`, and so is not part of a
+/// template checked out from a repository. Other hidden directories, like `.github`, are.
+pub fn is_vcs_dir(name: &OsStr) -> bool {
+    VCS_DIRS.iter().any(|dir| name == *dir)
+}
+
+/// Files of the shared directories among the embedded templates, with paths relative to the
+/// templates root, such as `_partials/labels.dockerfile`
+pub fn embedded_shared_files(templates: &Dir) -> Result<Vec<TemplateFile>> {
+    let mut files = Vec::new();
+    for dir in templates.dirs() {
+        if is_shared_dir(&dir.path().to_string_lossy()) {
+            collect_embedded_files(dir, templates.path(), &mut files)?;
+        }
+    }
+    Ok(files)
+}
+
+/// Files of the shared directories in a local templates directory, with paths relative to
+/// the templates directory
+pub fn local_shared_files(templates_dir: &Path) -> Result<Vec<TemplateFile>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(templates_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || !is_shared_dir(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        let mut shared = read_dir_files(&entry.path())?;
+        for file in &mut shared {
+            file.path = PathBuf::from(entry.file_name()).join(&file.path);
+        }
+        files.extend(shared);
+    }
+    Ok(files)
+}
+
+/// List the template directories inside a local templates directory.
+///
+/// Hidden directories are skipped so that things like `.git` never show up as templates,
+/// and so are shared directories such as `_partials`.
+pub fn local_template_dirs(templates_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(templates_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || is_shared_dir(&name) {
+            continue;
+        }
+        dirs.push((name, entry.path()));
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_binary() {
+        let file = |contents: &[u8]| TemplateFile {
+            path: PathBuf::from("file"),
+            contents: contents.to_vec(),
+            mode: None,
+        };
+        assert!(!file(b"# {{ image_name }}\n").is_binary());
+        assert!(!file("caf\u{e9}\n".as_bytes()).is_binary());
+        assert!(!file(b"").is_binary());
+        assert!(file(b"PK\x03\x04\x00\x00").is_binary());
+        assert!(file(b"latin-1 caf\xe9").is_binary());
+    }
+
+    #[test]
+    fn test_manifest_utf8_error() {
+        let manifest = TemplateFile {
+            path: PathBuf::from(MANIFEST_FILE),
+            contents: b"description = \"ok\"\nversion = \"caf\xe9\"\n".to_vec(),
+            mode: None,
+        };
+        let Err(Error::Diagnostic(diagnostic)) =
+            Template::new("basic", TemplateSource::Builtin, vec![manifest])
+        else {
+            panic!("expected a diagnostic");
+        };
+        assert_eq!(diagnostic.template.as_deref(), Some("basic"));
+        assert_eq!(diagnostic.file, PathBuf::from(MANIFEST_FILE));
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), Some(15)));
+    }
+}
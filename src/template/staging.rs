@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+use crate::error::{Error, Result};
+
+/// A scratch directory that generated files are written to before they reach the output
+/// directory.
+///
+/// Nothing appears at the output directory until [`Staging::commit`] is called. Dropping the
+/// staging directory without committing removes everything written to it.
+pub struct Staging {
+    dir: TempDir,
+    target: PathBuf,
+}
+
+impl Staging {
+    /// Create a staging directory for `target`.
+    ///
+    /// When the target already exists the staging directory is created inside it, otherwise
+    /// next to it, so the final moves never cross filesystems.
+    pub fn new(target: &Path) -> Result<Self> {
+        let dir = if target.is_dir() {
+            tempfile::Builder::new()
+                .prefix(".essex-")
+                .tempdir_in(target)?
+        } else {
+            let parent = match target.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            std::fs::create_dir_all(parent)?;
+
+            let name = target
+                .file_name()
+                .ok_or_else(|| Error::InvalidPath(target.display().to_string()))?
+                .to_string_lossy();
+            tempfile::Builder::new()
+                .prefix(&format!(".{}.essex-", name))
+                .tempdir_in(parent)?
+        };
+
+        Ok(Self {
+            dir,
+            target: target.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Move the staged files into the output directory.
+    ///
+    /// A missing output directory is created in a single rename. Otherwise the staged files
+    /// are moved over one by one, replacing files with the same path.
+    pub fn commit(self) -> Result<()> {
+        if !self.target.exists() {
+            std::fs::rename(self.dir.path(), &self.target)
+                .map_err(|e| Error::io_at(&self.target, e))?;
+            return Ok(());
+        }
+
+        for entry in WalkDir::new(self.dir.path()).min_depth(1) {
+            let entry = entry.map_err(|e| Error::InvalidPath(e.to_string()))?;
+            let rel_path = entry.path().strip_prefix(self.dir.path())?;
+            let output_path = self.target.join(rel_path);
+
+            if entry.file_type().is_dir() {
+                std::fs::create_dir_all(&output_path).map_err(|e| Error::io_at(&output_path, e))?;
+            } else {
+                std::fs::rename(entry.path(), &output_path)
+                    .map_err(|e| Error::io_at(&output_path, e))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_staging_commit_new_dir() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let target = temp_dir.path().join("project");
+
+        let staging = Staging::new(&target)?;
+        assert!(staging.path().starts_with(temp_dir.path()));
+        std::fs::create_dir_all(staging.path().join("src"))?;
+        std::fs::write(staging.path().join("src/main.rs"), "fn main() {}")?;
+        assert!(!target.exists());
+
+        staging.commit()?;
+        assert!(target.join("src/main.rs").exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_staging_commit_into_existing_dir() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let target = temp_dir.path().join("project");
+        std::fs::create_dir_all(&target)?;
+        std::fs::write(target.join("keep.txt"), "keep")?;
+        std::fs::write(target.join("README.md"), "old")?;
+
+        let staging = Staging::new(&target)?;
+        std::fs::write(staging.path().join("README.md"), "new")?;
+        staging.commit()?;
+
+        assert_eq!(std::fs::read_to_string(target.join("keep.txt"))?, "keep");
+        assert_eq!(std::fs::read_to_string(target.join("README.md"))?, "new");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+        assert_eq!(std::fs::read_dir(&target)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_staging_commit_errors_name_the_file() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let target = temp_dir.path().join("project");
+        std::fs::create_dir_all(&target)?;
+        std::fs::write(target.join("src"), "not a directory")?;
+
+        let staging = Staging::new(&target)?;
+        std::fs::create_dir_all(staging.path().join("src"))?;
+        std::fs::write(staging.path().join("src/main.rs"), "fn main() {}")?;
+
+        let error = staging.commit().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains(&target.join("src").display().to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_staging_dropped_without_commit() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let target = temp_dir.path().join("project");
+
+        let staging = Staging::new(&target)?;
+        std::fs::write(staging.path().join("README.md"), "partial")?;
+        drop(staging);
+
+        assert!(!target.exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+        Ok(())
+    }
+}
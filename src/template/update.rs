@@ -0,0 +1,388 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::archive::to_hex;
+use super::{RenderedFile, TemplateContext};
+use crate::error::{Error, Result};
+
+/// File written to the root of a generated project recording how it was generated
+pub const ANSWERS_FILE: &str = ".essex-answers";
+
+/// Everything needed to re-render a project: the template, the essex version and the
+/// answers it was generated with, plus the files as they were rendered at the time.
+///
+/// The rendered text files are the common ancestor for the three-way merge done by
+/// `essex update`. Binary and copied files are not merged, so only their SHA-256 is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Answers {
+    pub template: String,
+    /// Commit the template was rendered from, for templates fetched from git
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    pub essex_version: String,
+    pub context: TemplateContext,
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+    /// SHA-256 of the binary and copied files, by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<PathBuf, String>,
+}
+
+impl Answers {
+    /// Record the rendered `files`, of which those in `copied` were copied without rendering
+    pub fn new(
+        template: &str,
+        context: &TemplateContext,
+        files: &[RenderedFile],
+        copied: &[PathBuf],
+    ) -> Self {
+        let mut texts = BTreeMap::new();
+        let mut hashes = BTreeMap::new();
+        for file in files {
+            match std::str::from_utf8(&file.contents) {
+                Ok(content) if !copied.contains(&file.path) => {
+                    texts.insert(file.path.clone(), content.to_string());
+                }
+                _ => {
+                    hashes.insert(file.path.clone(), sha256(&file.contents));
+                }
+            }
+        }
+
+        Self {
+            template: template.to_string(),
+            commit: None,
+            essex_version: env!("CARGO_PKG_VERSION").to_string(),
+            context: context.clone(),
+            files: texts,
+            hashes,
+        }
+    }
+
+    /// Read the answers file from the root of a generated project
+    pub fn load(project_dir: &Path) -> Result<Self> {
+        let path = project_dir.join(ANSWERS_FILE);
+        if !path.is_file() {
+            return Err(Error::InvalidPath(format!(
+                "'{}' was not generated by essex (no {} file)",
+                project_dir.display(),
+                ANSWERS_FILE
+            )));
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| Error::InvalidValues(format!("{}: {}", path.display(), e)))
+    }
+
+    /// The answers file as a file to write alongside the rendered ones
+    pub fn to_file(&self) -> Result<RenderedFile> {
+        let mut contents = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::InvalidValues(format!("{}: {}", ANSWERS_FILE, e)))?;
+        contents.push(b'\n');
+
+        Ok(RenderedFile {
+            path: PathBuf::from(ANSWERS_FILE),
+            contents,
+            mode: 0o644,
+        })
+    }
+}
+
+/// What happened to a file during an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateStatus {
+    /// The file is new in the template and was created
+    Added,
+    /// The template change was applied cleanly
+    Updated,
+    /// The template change and local edits were merged cleanly
+    Merged,
+    /// The template change and local edits overlap. Conflict markers were written into text
+    /// files; binary and copied files were left as they are.
+    Conflict,
+    /// The file was deleted locally and left alone
+    Skipped,
+}
+
+impl std::fmt::Display for UpdateStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateStatus::Added => write!(f, "added"),
+            UpdateStatus::Updated => write!(f, "updated"),
+            UpdateStatus::Merged => write!(f, "merged"),
+            UpdateStatus::Conflict => write!(f, "conflict"),
+            UpdateStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Files touched by an update and what happened to each
+pub type UpdateReport = Vec<(PathBuf, UpdateStatus)>;
+
+/// Three-way merge freshly rendered files into a project.
+///
+/// `base` holds the files as they were last rendered, the project directory holds the
+/// user's edited copies and `files` the new rendering. Returns the files to write and what
+/// happened to each file that changed; unchanged files are left out.
+///
+/// Binary and copied files are never merged: they are replaced when the user has not touched
+/// them, and otherwise left alone and reported as a conflict.
+pub fn merge_update(
+    project_dir: &Path,
+    base: &Answers,
+    files: &[RenderedFile],
+) -> Result<(Vec<RenderedFile>, UpdateReport)> {
+    let mut writes = Vec::new();
+    let mut report = Vec::new();
+
+    for file in files {
+        let current_path = project_dir.join(&file.path);
+        let current = if current_path.is_file() {
+            Some(std::fs::read(&current_path)?)
+        } else {
+            None
+        };
+        let opaque = base.hashes.contains_key(&file.path)
+            || std::str::from_utf8(&file.contents).is_err()
+            || current
+                .as_deref()
+                .is_some_and(|current| std::str::from_utf8(current).is_err());
+        if opaque {
+            if let Some(status) = replace_opaque(base, file, current.as_deref()) {
+                if status != UpdateStatus::Conflict && status != UpdateStatus::Skipped {
+                    writes.push(file.clone());
+                }
+                report.push((file.path.clone(), status));
+            }
+            continue;
+        }
+
+        let ancestor = base.files.get(&file.path).map(|s| s.as_bytes());
+        let (contents, status) = match (ancestor, current) {
+            // New in the template
+            (None, None) => (file.contents.clone(), UpdateStatus::Added),
+            // Deleted by the user
+            (Some(_), None) => {
+                report.push((file.path.clone(), UpdateStatus::Skipped));
+                continue;
+            }
+            (_, Some(current)) if current == file.contents => continue,
+            // Untouched by the user, or unchanged in the template
+            (Some(ancestor), Some(_)) if ancestor == file.contents.as_slice() => continue,
+            (Some(ancestor), Some(current)) if ancestor == current.as_slice() => {
+                (file.contents.clone(), UpdateStatus::Updated)
+            }
+            (ancestor, Some(current)) => {
+                match diffy::merge_bytes(ancestor.unwrap_or_default(), &current, &file.contents) {
+                    Ok(merged) => (merged, UpdateStatus::Merged),
+                    Err(conflicted) => (conflicted, UpdateStatus::Conflict),
+                }
+            }
+        };
+
+        writes.push(RenderedFile {
+            path: file.path.clone(),
+            contents,
+            mode: file.mode,
+        });
+        report.push((file.path.clone(), status));
+    }
+
+    Ok((writes, report))
+}
+
+/// What to do with a binary or copied file, compared by hash with the version it was last
+/// rendered as. `None` when there is nothing to do.
+fn replace_opaque(
+    base: &Answers,
+    file: &RenderedFile,
+    current: Option<&[u8]>,
+) -> Option<UpdateStatus> {
+    let ancestor = match base.hashes.get(&file.path) {
+        Some(hash) => Some(hash.clone()),
+        // Kept in full as a text file, as older versions of essex did for copied files
+        None => base
+            .files
+            .get(&file.path)
+            .map(|text| sha256(text.as_bytes())),
+    };
+
+    match (ancestor, current) {
+        (None, None) => Some(UpdateStatus::Added),
+        (Some(_), None) => Some(UpdateStatus::Skipped),
+        (_, Some(current)) if current == file.contents => None,
+        (Some(ancestor), Some(_)) if ancestor == sha256(&file.contents) => None,
+        (Some(ancestor), Some(current)) if ancestor == sha256(current) => {
+            Some(UpdateStatus::Updated)
+        }
+        (_, Some(_)) => Some(UpdateStatus::Conflict),
+    }
+}
+
+fn sha256(contents: &[u8]) -> String {
+    to_hex(&Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn rendered(path: &str, contents: &str) -> RenderedFile {
+        RenderedFile {
+            path: PathBuf::from(path),
+            contents: contents.as_bytes().to_vec(),
+            mode: 0o644,
+        }
+    }
+
+    #[test]
+    fn test_answers_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let context = TemplateContext::new("acme/app", Some("ci".to_string()), None)?
+            .with_values([("port".to_string(), 8080.into())]);
+        let answers = Answers::new("basic", &context, &[rendered("Makefile", "all:\n")], &[]);
+
+        let file = answers.to_file()?;
+        std::fs::write(dir.path().join(&file.path), &file.contents)?;
+
+        let loaded = Answers::load(dir.path())?;
+        assert_eq!(loaded.template, "basic");
+        assert_eq!(loaded.essex_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(loaded.context.repo_username, "ci");
+        assert_eq!(loaded.context.build_date, context.build_date);
+        assert_eq!(loaded.context.values["port"], serde_json::json!(8080));
+        assert_eq!(loaded.files[Path::new("Makefile")], "all:\n");
+
+        // Overriding a built-in variable records it once, in place of the built-in value
+        let context = context.with_values([
+            ("version".to_string(), "2.0.0".into()),
+            ("vcs_ref".to_string(), "abc1234".into()),
+        ]);
+        let file = Answers::new("basic", &context, &[], &[]).to_file()?;
+        std::fs::write(dir.path().join(&file.path), &file.contents)?;
+        let loaded = Answers::load(dir.path())?;
+        assert_eq!(loaded.context.version, "2.0.0");
+        assert_eq!(loaded.context.vcs_ref, "abc1234");
+        assert!(!loaded.context.values.contains_key("version"));
+
+        let missing = tempdir()?;
+        assert!(matches!(
+            Answers::load(missing.path()),
+            Err(Error::InvalidPath(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_update() -> Result<()> {
+        let dir = tempdir()?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+        let base = Answers::new(
+            "basic",
+            &context,
+            &[
+                rendered("untouched.txt", "one\n"),
+                rendered("edited.txt", "a\nb\nc\nd\ne\n"),
+                rendered("conflict.txt", "x\n"),
+                rendered("deleted.txt", "gone\n"),
+                rendered("same.txt", "same\n"),
+            ],
+            &[],
+        );
+
+        std::fs::write(dir.path().join("untouched.txt"), "one\n")?;
+        std::fs::write(dir.path().join("edited.txt"), "a\nB\nc\nd\ne\n")?;
+        std::fs::write(dir.path().join("conflict.txt"), "mine\n")?;
+        std::fs::write(dir.path().join("same.txt"), "local\n")?;
+
+        let new_files = vec![
+            rendered("untouched.txt", "two\n"),
+            rendered("edited.txt", "a\nb\nc\nd\nE\n"),
+            rendered("conflict.txt", "theirs\n"),
+            rendered("deleted.txt", "changed\n"),
+            rendered("same.txt", "same\n"),
+            rendered("added.txt", "new\n"),
+        ];
+
+        let (writes, report) = merge_update(dir.path(), &base, &new_files)?;
+        let status: BTreeMap<_, _> = report
+            .iter()
+            .map(|(path, status)| (path.to_string_lossy().into_owned(), *status))
+            .collect();
+        assert_eq!(status["untouched.txt"], UpdateStatus::Updated);
+        assert_eq!(status["edited.txt"], UpdateStatus::Merged);
+        assert_eq!(status["conflict.txt"], UpdateStatus::Conflict);
+        assert_eq!(status["deleted.txt"], UpdateStatus::Skipped);
+        assert_eq!(status["added.txt"], UpdateStatus::Added);
+        assert!(!status.contains_key("same.txt"));
+
+        let contents: BTreeMap<_, _> = writes
+            .iter()
+            .map(|f| {
+                (
+                    f.path.to_string_lossy().into_owned(),
+                    String::from_utf8(f.contents.clone()).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(contents["untouched.txt"], "two\n");
+        assert_eq!(contents["edited.txt"], "a\nB\nc\nd\nE\n");
+        assert!(contents["conflict.txt"].contains("<<<<<<<"));
+        assert!(contents["conflict.txt"].contains("mine\n"));
+        assert!(contents["conflict.txt"].contains("theirs\n"));
+        assert_eq!(contents["added.txt"], "new\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_update_binary_and_copied_files() -> Result<()> {
+        let dir = tempdir()?;
+        let context = TemplateContext::new("acme/app", None, None)?;
+        let binary = |bytes: &[u8]| RenderedFile {
+            path: PathBuf::from("icon.png"),
+            contents: bytes.to_vec(),
+            mode: 0o644,
+        };
+        let base = Answers::new(
+            "basic",
+            &context,
+            &[
+                binary(b"\x89PNG\x00old"),
+                rendered("vendor.js", "v1\n"),
+                rendered("edited.js", "v1\n"),
+            ],
+            &[PathBuf::from("vendor.js"), PathBuf::from("edited.js")],
+        );
+        assert!(base.files.is_empty());
+        assert_eq!(base.hashes.len(), 3);
+
+        std::fs::write(dir.path().join("icon.png"), b"\x89PNG\x00old")?;
+        std::fs::write(dir.path().join("vendor.js"), "v1\n")?;
+        std::fs::write(dir.path().join("edited.js"), "mine\n")?;
+
+        let new_files = vec![
+            binary(b"\x89PNG\x00new"),
+            rendered("vendor.js", "v2\n"),
+            rendered("edited.js", "v2\n"),
+        ];
+        let (writes, report) = merge_update(dir.path(), &base, &new_files)?;
+        assert_eq!(
+            report,
+            vec![
+                (PathBuf::from("icon.png"), UpdateStatus::Updated),
+                (PathBuf::from("vendor.js"), UpdateStatus::Updated),
+                (PathBuf::from("edited.js"), UpdateStatus::Conflict),
+            ]
+        );
+        // Replaced byte for byte, and the locally edited copy is left alone
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].contents, b"\x89PNG\x00new");
+        assert_eq!(writes[1].contents, b"v2\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// Values supplied for template variables, keyed by variable name
+pub type Values = BTreeMap<String, Value>;
+
+/// Parse a `key=value` pair as given to `--set`.
+///
+/// The value is kept as a string; declared variables are converted to their type when the
+/// template manifest is resolved.
+pub fn parse_set(arg: &str) -> Result<(String, Value)> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| Error::InvalidValues(format!("expected KEY=VALUE, got '{}'", arg)))?;
+
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(Error::InvalidValues(format!("missing key in '{}'", arg)));
+    }
+
+    Ok((key.to_string(), Value::String(value.to_string())))
+}
+
+/// Load values from a JSON, YAML or TOML file, picked by extension
+pub fn load_values_file(path: &Path) -> Result<Values> {
+    parse_values(&std::fs::read_to_string(path)?, path)
+}
+
+/// Parse values read from `path`, in the format its extension names
+pub fn parse_values(content: &str, path: &Path) -> Result<Values> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+
+    let invalid =
+        |e: &dyn std::fmt::Display| Error::InvalidValues(format!("{}: {}", path.display(), e));
+
+    let value: Value = match extension {
+        "json" => serde_json::from_str(content).map_err(|e| invalid(&e))?,
+        "yaml" | "yml" => serde_yaml::from_str(content).map_err(|e| invalid(&e))?,
+        "toml" => toml::from_str(content).map_err(|e| invalid(&e))?,
+        _ => {
+            return Err(invalid(
+                &"unsupported values file, expected .json, .yaml, .yml or .toml",
+            ))
+        }
+    };
+
+    match value {
+        Value::Object(map) => Ok(map.into_iter().collect()),
+        Value::Null => Ok(Values::new()),
+        _ => Err(invalid(&"expected a map of variable names to values")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_set() {
+        let (key, value) = parse_set("version=1.2.3").unwrap();
+        assert_eq!(key, "version");
+        assert_eq!(value, json!("1.2.3"));
+
+        let (key, value) = parse_set("url=http://x?a=b").unwrap();
+        assert_eq!(key, "url");
+        assert_eq!(value, json!("http://x?a=b"));
+
+        let (_, value) = parse_set("empty=").unwrap();
+        assert_eq!(value, json!(""));
+
+        assert!(matches!(parse_set("novalue"), Err(Error::InvalidValues(_))));
+        assert!(matches!(parse_set("=value"), Err(Error::InvalidValues(_))));
+    }
+
+    #[test]
+    fn test_load_values_file() -> Result<()> {
+        let dir = tempdir()?;
+
+        let json_path = dir.path().join("answers.json");
+        std::fs::write(&json_path, r#"{"version": "1.0.0", "port": 8080}"#)?;
+        let values = load_values_file(&json_path)?;
+        assert_eq!(values["version"], json!("1.0.0"));
+        assert_eq!(values["port"], json!(8080));
+
+        let yaml_path = dir.path().join("answers.yaml");
+        std::fs::write(&yaml_path, "version: 1.0.0\nwith_compose: true\n")?;
+        let values = load_values_file(&yaml_path)?;
+        assert_eq!(values["version"], json!("1.0.0"));
+        assert_eq!(values["with_compose"], json!(true));
+
+        let toml_path = dir.path().join("answers.toml");
+        std::fs::write(
+            &toml_path,
+            "version = \"1.0.0\"\n[labels]\nteam = \"platform\"\n",
+        )?;
+        let values = load_values_file(&toml_path)?;
+        assert_eq!(values["labels"]["team"], json!("platform"));
+
+        let list_path = dir.path().join("list.json");
+        std::fs::write(&list_path, "[1, 2]")?;
+        assert!(matches!(
+            load_values_file(&list_path),
+            Err(Error::InvalidValues(_))
+        ));
+
+        let txt_path = dir.path().join("answers.txt");
+        std::fs::write(&txt_path, "version=1")?;
+        assert!(matches!(
+            load_values_file(&txt_path),
+            Err(Error::InvalidValues(_))
+        ));
+
+        Ok(())
+    }
+}
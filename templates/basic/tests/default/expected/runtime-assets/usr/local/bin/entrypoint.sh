@@ -0,0 +1,4 @@
+#!/bin/sh
+set -e
+
+exec "$@"
\ No newline at end of file
@@ -61,5 +61,842 @@ fn test_invalid_template() {
 
     assert
         .failure()
-        .stderr(predicate::str::contains("TemplateNotFound"));
+        .stderr(predicate::str::contains("Template not found: nonexistent"));
+}
+
+#[test]
+fn test_local_templates_dir() {
+    let temp = setup_test_dir();
+    let templates = temp.child("templates");
+    templates
+        .child("house/README.md")
+        .write_str("# {{ image_name }} by {{ vendor }}")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .env("ESSEX_TEMPLATES_PATH", templates.path())
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("basic (built-in)"))
+        .stdout(predicate::str::contains("house ("));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "house", "acme/app"])
+        .args(["--vendor", "Acme"])
+        .assert()
+        .success();
+
+    temp.child("acme/app/README.md")
+        .assert(predicate::str::diff("# app by Acme"));
+}
+
+#[test]
+fn test_template_errors_with_location() {
+    let temp = setup_test_dir();
+    temp.child("templates/broken/Dockerfile")
+        .write_str("FROM scratch\nLABEL vendor={{ vendor | }}\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "broken", "acme/app"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--> Dockerfile:2:26 in template 'broken'",
+        ))
+        .stderr(predicate::str::contains(
+            "2 | LABEL vendor={{ vendor | }}\n  |                          ^",
+        ));
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "broken", "acme/app"])
+        .arg("--json")
+        .assert()
+        .failure()
+        .get_output()
+        .stderr
+        .clone();
+    let error: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(error["error"]["kind"], "template");
+    assert_eq!(error["error"]["template"], "broken");
+    assert_eq!(error["error"]["file"], "Dockerfile");
+    assert_eq!(error["error"]["line"], 2);
+    assert_eq!(error["error"]["column"], 26);
+}
+
+#[test]
+fn test_structured_output() {
+    let temp = setup_test_dir();
+    temp.child("templates/svc/essex.toml")
+        .write_str(
+            "description = \"Service\"\nversion = \"1.0.0\"\n\n\
+             [[variables]]\nname = \"port\"\ntype = \"integer\"\ndefault = 8080\n",
+        )
+        .unwrap();
+    temp.child("templates/svc/run.sh")
+        .write_str("#!/bin/sh\nexec app --port {{ port }}\n")
+        .unwrap();
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "list", "--format", "json"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let templates: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let svc = templates
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|t| t["name"] == "svc")
+        .unwrap();
+    assert_eq!(svc["description"], "Service");
+    assert_eq!(svc["version"], "1.0.0");
+    assert_eq!(svc["variables"][0]["name"], "port");
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "svc", "acme/app"])
+        .args(["--format", "json", "--no-input"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["context"]["port"], 8080);
+    assert_eq!(report["context"]["image_name"], "app");
+    let script = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|f| f["path"] == "run.sh")
+        .unwrap();
+    assert_eq!(script["mode"], "0755");
+    assert_eq!(script["size"], 31);
+    assert_eq!(script["sha256"].as_str().unwrap().len(), 64);
+    temp.child("acme/app/run.sh")
+        .assert(predicate::str::contains("--port 8080"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "svc", "acme/other"])
+        .args(["--format", "yaml", "--dry-run", "--set", "port=9090"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("dry_run: true"))
+        .stdout(predicate::str::contains("port: 9090"))
+        .stdout(predicate::str::contains("Creating").not());
+}
+
+#[test]
+fn test_info_command() {
+    let temp = setup_test_dir();
+    temp.child("templates/svc/essex.toml")
+        .write_str(
+            "description = \"Service\"\n\n\
+             [[variables]]\nname = \"port\"\ntype = \"integer\"\ndefault = 8080\n",
+        )
+        .unwrap();
+    temp.child("templates/svc/bin/run.sh")
+        .write_str("#!/bin/sh\nexec {{ image_name }} --port {{ port }} --team {{ team }}\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "info", "svc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Service"))
+        .stdout(predicate::str::is_match(r"port\s+default 8080").unwrap())
+        .stdout(predicate::str::is_match(r"team\s+required").unwrap())
+        .stdout(predicate::str::is_match(r"bin/run.sh\s+0755\s+executable").unwrap());
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args([
+            "--templates-dir",
+            "templates",
+            "info",
+            "svc",
+            "--format",
+            "json",
+        ])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let info: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let names: Vec<&str> = info["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["image_name", "port", "team"]);
+    assert_eq!(info["files"][0]["executable"], true);
+    temp.child("acme").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_template_lint_command() {
+    let temp = setup_test_dir();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .args(["template", "lint", "basic"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("No problems found in 'basic'"));
+
+    temp.child("svc/run.sh")
+        .write_str("exec app --port {{ port }}\n")
+        .unwrap();
+    temp.child("svc/Makefile")
+        .write_str("ps:\n    docker ps --format '{{.Names}}'\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["template", "lint", "svc"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("error[shebang]"))
+        .stdout(predicate::str::contains(
+            "error[undefined-variable]: `port`",
+        ))
+        .stdout(predicate::str::contains(
+            "--> Makefile:2:1 in template 'svc'",
+        ))
+        .stdout(predicate::str::contains("error[docker-format]"))
+        .stdout(predicate::str::contains("4 error(s), 0 warning(s)"))
+        .stderr(predicate::str::contains("svc: 4 error(s) found"));
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--format", "json", "template", "lint", "svc"])
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let rules: Vec<&str> = report["findings"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["rule"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        rules,
+        vec![
+            "makefile-tabs",
+            "docker-format",
+            "shebang",
+            "undefined-variable"
+        ]
+    );
+}
+
+#[test]
+fn test_template_test_command() {
+    let temp = setup_test_dir();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .args(["template", "test", "basic"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("test default ... ok"));
+
+    temp.child("svc/README.md")
+        .write_str("# {{ image_name }} on port {{ port }}\n")
+        .unwrap();
+    temp.child("svc/tests/default/answers.yaml")
+        .write_str("project: acme/api\nport: 8080\n")
+        .unwrap();
+    temp.child("svc/tests/default/expected/README.md")
+        .write_str("# api on port 9090\n")
+        .unwrap();
+    temp.child("svc/tests/default/expected/LICENSE")
+        .write_str("MIT\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["template", "test", "svc"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("test default ... FAILED"))
+        .stdout(predicate::str::contains("missing: LICENSE"))
+        .stdout(predicate::str::contains(
+            "--- expected/README.md\n+++ rendered/README.md\n",
+        ))
+        .stdout(predicate::str::contains(
+            "-# api on port 9090\n+# api on port 8080\n",
+        ))
+        .stderr(predicate::str::contains("svc: 1 test case(s) failed"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["template", "test", "svc", "--bless"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "test default ... blessed (2 file(s))",
+        ));
+    temp.child("svc/tests/default/expected/README.md")
+        .assert("# api on port 8080\n");
+    temp.child("svc/tests/default/expected/LICENSE")
+        .assert(predicate::path::missing());
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["template", "test", "svc"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 passed; 0 failed"));
+}
+
+#[test]
+fn test_new_command_with_values() {
+    let temp = setup_test_dir();
+    temp.child("answers.yaml")
+        .write_str("version: 1.4.0\nauthor_email: ops@acme.io\nvcs_ref: abc123\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--values", "answers.yaml"])
+        .args(["--set", "vcs_ref=def456"])
+        .assert()
+        .success();
+
+    let dockerfile = temp.child("acme/app/Dockerfile");
+    dockerfile.assert(predicate::str::contains(
+        "org.opencontainers.image.authors=\"example <ops@acme.io>\"",
+    ));
+    dockerfile.assert(predicate::str::contains(
+        "org.opencontainers.image.version=\"1.4.0\"",
+    ));
+    dockerfile.assert(predicate::str::contains(
+        "org.opencontainers.image.revision=\"def456\"",
+    ));
+}
+
+#[test]
+fn test_new_command_rejects_path_overrides() {
+    let temp = setup_test_dir();
+
+    for value in ["image_name=../../escaped", "repo_namespace=/tmp"] {
+        Command::cargo_bin("essex")
+            .unwrap()
+            .current_dir(&temp)
+            .args(["new", "basic", "acme/app", "--no-input", "--set", value])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("Invalid project name"));
+    }
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--no-input", "--set"])
+        .arg("repo_username=John Doe")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("repo_username"));
+
+    temp.child("acme").assert(predicate::path::missing());
+    temp.child("../escaped").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_new_command_dry_run() {
+    let temp = setup_test_dir();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("acme/app/\n"))
+        .stdout(predicate::str::contains("├── Dockerfile (0644, "))
+        .stdout(predicate::str::contains("entrypoint.sh (0755, "));
+
+    temp.child("acme").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_new_command_diff() {
+    let temp = setup_test_dir();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app"])
+        .assert()
+        .success();
+
+    temp.child("acme/app/README.md")
+        .write_str("# app\n\nEdited by hand.\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--diff"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "--- a/README.md\n+++ b/README.md\n",
+        ))
+        .stdout(predicate::str::contains("-Edited by hand.\n"))
+        .stdout(predicate::str::contains("Dockerfile").not());
+
+    let output = Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--diff", "--format", "json"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(report["directory"], "acme/app");
+    let status = |path: &str| {
+        let files = report["files"].as_array().unwrap();
+        let file = files.iter().find(|file| file["path"] == path).unwrap();
+        (
+            file["status"].clone(),
+            file["diff"].as_str().unwrap().to_string(),
+        )
+    };
+    let (readme, diff) = status("README.md");
+    assert_eq!(readme, "modified");
+    assert!(diff.contains("-Edited by hand.\n"));
+    assert_eq!(status("Dockerfile"), ("unchanged".into(), String::new()));
+
+    temp.child("acme/app/README.md")
+        .assert(predicate::str::contains("Edited by hand."));
+}
+
+#[test]
+fn test_update_command() {
+    let temp = setup_test_dir();
+    let templates = temp.child("templates");
+    templates
+        .child("house/Makefile")
+        .write_str("IMAGE ?= {{ image_name }}\n\nbuild:\n\tdocker build .\n")
+        .unwrap();
+    templates
+        .child("house/README.md")
+        .write_str("# {{ image_name }}\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .env("ESSEX_TEMPLATES_PATH", templates.path())
+        .args(["new", "house", "acme/app", "--vendor", "Acme"])
+        .assert()
+        .success();
+    temp.child("acme/app/.essex-answers")
+        .assert(predicate::str::contains("\"template\": \"house\""));
+
+    // The user edits the project and the template gains a new target and a new file
+    temp.child("acme/app/Makefile")
+        .write_str("IMAGE ?= app\nTAG ?= dev\n\nbuild:\n\tdocker build .\n")
+        .unwrap();
+    templates
+        .child("house/Makefile")
+        .write_str(
+            "IMAGE ?= {{ image_name }}\n\nbuild:\n\tdocker build .\n\npush:\n\tdocker push\n",
+        )
+        .unwrap();
+    templates
+        .child("house/.dockerignore")
+        .write_str(".git\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .env("ESSEX_TEMPLATES_PATH", templates.path())
+        .args(["update", "acme/app"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("merged    Makefile"))
+        .stdout(predicate::str::contains("added     .dockerignore"))
+        .stdout(predicate::str::contains("README.md").not());
+
+    temp.child("acme/app/Makefile").assert(predicate::str::diff(
+        "IMAGE ?= app\nTAG ?= dev\n\nbuild:\n\tdocker build .\n\npush:\n\tdocker push\n",
+    ));
+    temp.child("acme/app/.dockerignore")
+        .assert(predicate::path::exists());
+
+    // A second update has nothing left to do
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .env("ESSEX_TEMPLATES_PATH", templates.path())
+        .args(["update", "acme/app"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Project is up to date."));
+}
+
+#[test]
+fn test_new_command_output_and_in_place() {
+    let temp = setup_test_dir();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["new", "basic", "acme/app", "--output", "services/app"])
+        .assert()
+        .success();
+    temp.child("services/app/Dockerfile")
+        .assert(predicate::path::exists());
+    temp.child("acme").assert(predicate::path::missing());
+
+    // A git-initialized directory can be generated into in place
+    let repo = temp.child("repo");
+    repo.child(".git/HEAD")
+        .write_str("ref: refs/heads/main\n")
+        .unwrap();
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place"])
+        .assert()
+        .success();
+    repo.child("Makefile").assert(predicate::path::exists());
+    repo.child(".git/HEAD").assert(predicate::path::exists());
+
+    // Existing files need an overwrite policy
+    repo.child("README.md").write_str("mine\n").unwrap();
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not empty"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place", "--json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(r#""kind":"invalid_path""#));
+
+    // There is no terminal to ask on, so --prompt refuses rather than answering no
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place", "--prompt"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--prompt needs a terminal"));
+    repo.child("README.md")
+        .assert(predicate::str::diff("mine\n"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place", "--skip-existing"])
+        .assert()
+        .success();
+    repo.child("README.md")
+        .assert(predicate::str::diff("mine\n"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&repo)
+        .args(["new", "basic", "acme/app", "--in-place", "--force"])
+        .assert()
+        .success();
+    repo.child("README.md")
+        .assert(predicate::str::contains("# app"));
+}
+
+#[test]
+fn test_new_command_from_git() {
+    let temp = setup_test_dir();
+    let work = temp.child("work");
+    work.child("python/README.md")
+        .write_str("# {{ image_name }} from git")
+        .unwrap();
+
+    let git = |dir: &std::path::Path, args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.email=test@example.com", "-c", "user.name=Test"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?}", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+    git(work.path(), &["init", "--quiet"]);
+    git(work.path(), &["add", "."]);
+    git(work.path(), &["commit", "--quiet", "-m", "initial"]);
+    git(work.path(), &["tag", "v1.2"]);
+    let commit = git(work.path(), &["rev-parse", "HEAD"]);
+    git(
+        temp.path(),
+        &["clone", "--quiet", "--bare", "work", "templates.git"],
+    );
+
+    let reference = format!(
+        "git+file://{}#python@v1.2",
+        temp.child("templates.git").path().display()
+    );
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--cache-dir", "cache", "new", &reference, "acme/app"])
+        .arg("--no-input")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("at commit {}", commit)));
+
+    temp.child("acme/app/README.md")
+        .assert(predicate::str::diff("# app from git"));
+    temp.child("acme/app/.essex-answers")
+        .assert(predicate::str::contains(format!(
+            "\"commit\": \"{}\"",
+            commit
+        )));
+}
+
+#[test]
+fn test_template_pack_and_new_from_archive() {
+    let temp = setup_test_dir();
+    let template = temp.child("python-service");
+    template
+        .child("essex.toml")
+        .write_str("version = \"1.4\"\n")
+        .unwrap();
+    template
+        .child("README.md")
+        .write_str("# {{ image_name }} from an archive")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["template", "pack", "python-service"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("python-service-1.4.tar.gz"))
+        .stdout(predicate::str::contains("sha256: "));
+    temp.child("python-service-1.4.tar.gz.sha256")
+        .assert(predicate::str::ends_with("  python-service-1.4.tar.gz\n"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args([
+            "new",
+            "./python-service-1.4.tar.gz",
+            "acme/app",
+            "--no-input",
+        ])
+        .assert()
+        .success();
+    temp.child("acme/app/README.md")
+        .assert(predicate::str::diff("# app from an archive"));
+}
+
+#[test]
+fn test_new_command_hooks() {
+    let temp = setup_test_dir();
+    let templates = temp.child("templates");
+    templates
+        .child("hooked/essex.toml")
+        .write_str(
+            "[hooks]\npre = [\"touch pre-ran\", \"echo greeting=hello\"]\n\
+             post = [\"echo $ESSEX_GREETING $ESSEX_IMAGE_NAME > hooked.txt\"]\n",
+        )
+        .unwrap();
+    templates
+        .child("hooked/README.md")
+        .write_str("# {{ image_name }} {{ greeting | default(value='untrusted') }}")
+        .unwrap();
+
+    // Without --trust and without a terminal, hooks are skipped
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args([
+            "--templates-dir",
+            "templates",
+            "new",
+            "hooked",
+            "acme/skipped",
+        ])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("use --trust to run them"));
+    temp.child("acme/skipped/README.md")
+        .assert(predicate::str::diff("# skipped untrusted"));
+    temp.child("acme/skipped/hooked.txt")
+        .assert(predicate::path::missing());
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "hooked", "acme/app"])
+        .arg("--trust")
+        .assert()
+        .success();
+    temp.child("acme/app/README.md")
+        .assert(predicate::str::diff("# app hello"));
+    temp.child("acme/app/hooked.txt")
+        .assert(predicate::str::diff("hello app\n"));
+
+    // An occupied directory is refused before the hooks run
+    std::fs::remove_file(temp.child("pre-ran").path()).unwrap();
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "new", "hooked", "acme/app"])
+        .arg("--trust")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists and is not empty"));
+    temp.child("pre-ran").assert(predicate::path::missing());
+
+    // Values from hooks are checked like any other
+    templates
+        .child("moving/essex.toml")
+        .write_str("[hooks]\npre = [\"echo image_name=../../escaped\"]\n")
+        .unwrap();
+    templates
+        .child("moving/README.md")
+        .write_str("# {{ image_name }}")
+        .unwrap();
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args([
+            "--templates-dir",
+            "templates",
+            "new",
+            "moving",
+            "acme/moved",
+        ])
+        .arg("--trust")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid project name"));
+    temp.child("acme/moved").assert(predicate::path::missing());
+}
+
+#[test]
+fn test_new_command_composed_templates() {
+    let temp = setup_test_dir();
+    let templates = temp.child("templates");
+    templates
+        .child("compose/essex.toml")
+        .write_str("[[merge]]\nglob = \"Makefile\"\nstrategy = \"append\"\n")
+        .unwrap();
+    templates
+        .child("compose/Makefile")
+        .write_str("up:\n\tdocker compose up\n")
+        .unwrap();
+    templates
+        .child("compose/docker-compose.yml")
+        .write_str("services:\n  {{ image_name }}:\n    build: .\n")
+        .unwrap();
+    templates
+        .child("ci/essex.toml")
+        .write_str("[[merge]]\nglob = \"*.yml\"\nstrategy = \"yaml\"\n")
+        .unwrap();
+    templates
+        .child("ci/docker-compose.yml")
+        .write_str("services:\n  {{ image_name }}:\n    ports: ['8080:8080']\n")
+        .unwrap();
+    templates
+        .child("ci/README.md")
+        .write_str("# {{ image_name }} with CI\n")
+        .unwrap();
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args([
+            "--templates-dir",
+            "templates",
+            "new",
+            "basic+compose",
+            "acme/app",
+            "--with",
+            "ci",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Makefile (basic, compose): appended",
+        ))
+        .stdout(predicate::str::contains(
+            "docker-compose.yml (compose, ci): merged as YAML",
+        ))
+        .stdout(predicate::str::contains(
+            "README.md (basic, ci): replaced by ci",
+        ));
+
+    let project = temp.child("acme/app");
+    project
+        .child("Makefile")
+        .assert(predicate::str::contains("build:"))
+        .assert(predicate::str::ends_with("up:\n\tdocker compose up\n"));
+    project
+        .child("docker-compose.yml")
+        .assert(predicate::str::contains("build: ."))
+        .assert(predicate::str::contains("8080:8080"));
+    project
+        .child("README.md")
+        .assert(predicate::str::diff("# app with CI\n"));
+    project
+        .child(".essex-answers")
+        .assert(predicate::str::contains("basic+compose+ci"));
+
+    Command::cargo_bin("essex")
+        .unwrap()
+        .current_dir(&temp)
+        .args(["--templates-dir", "templates", "update", "acme/app"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Project is up to date."));
 }
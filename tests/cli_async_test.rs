@@ -76,7 +76,7 @@ async fn test_cli_async_template_error_handling() {
         .arg("invalid-project-name")  // Missing namespace
         .assert()
         .failure()
-        .stderr(predicate::str::contains("Error: InvalidProjectName"));
+        .stderr(predicate::str::contains("Error: Invalid project name"));
 
     // Test non-existent template
     let mut cmd = Command::cargo_bin("essex").unwrap();
@@ -85,7 +85,7 @@ async fn test_cli_async_template_error_handling() {
         .arg("test/project")
         .assert()
         .failure()
-        .stderr(predicate::str::contains("Error: TemplateNotFound"));
+        .stderr(predicate::str::contains("Error: Template not found"));
 }
 
 #[test]
@@ -40,7 +40,7 @@ proptest! {
         let temp_dir = tempdir().unwrap();
         let output_dir = temp_dir.path().join("test-output");
 
-        let mut engine = TemplateEngine::new(".").unwrap();
+        let engine = TemplateEngine::new(".").unwrap();
         let context = TemplateContext::new(
             &project,
             Some(username),
@@ -94,7 +94,7 @@ fn test_template_generation_error_cases() {
     let temp_dir = tempdir().unwrap();
     let output_dir = temp_dir.path().join("test-output");
 
-    let mut engine = TemplateEngine::new(".").unwrap();
+    let engine = TemplateEngine::new(".").unwrap();
 
     // Test with non-existent template
     let context = TemplateContext::new("test/project", None, None).unwrap();